@@ -0,0 +1,304 @@
+//! Load benchmark for the server: thousands of idle clients, plus steady chatter in a few rooms.
+//!
+//! Starts the server binary on a free local port and connects every client to it from one asynchronous runtime.
+//! Run it with `cargo bench --bench load`. The load can be changed with environment variables:
+//! `LOAD_IDLE` idle clients (default 5000), `LOAD_CHATTERS` clients sending messages (default 50),
+//! `LOAD_ROOMS` rooms they are spread over (default 10), `LOAD_RATE` messages a second from each (default 5)
+//! and `LOAD_SECONDS` how long they chat for (default 10).
+
+use std::env;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_irc::framing::AsyncFrameReader;
+use rust_irc::protocol::{read_message_async, send_async, Message};
+use rust_irc::PROTOCOL_VERSION;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+type Reader = AsyncFrameReader<OwnedReadHalf>;
+
+/// Most clients connecting at once, so that the server's listen backlog does not overflow
+const CONNECTING_AT_ONCE: usize = 256;
+
+/// Idle clients are spread over lounges of this many, which they join without ever speaking in them.
+/// Every join is announced to the room, so one lounge for everyone would cost a broadcast per client
+const LOUNGE_SIZE: usize = 50;
+
+/// Counts shared by every client
+#[derive(Default)]
+struct Stats {
+    /// Idle clients which connected and registered
+    connected: AtomicUsize,
+    /// Clients which failed to connect or register
+    failed: AtomicUsize,
+    /// Clients the server disconnected during the run
+    dropped: AtomicUsize,
+    sent: AtomicUsize,
+    /// Microseconds from sending each room message to another member receiving it
+    latencies: Mutex<Vec<u64>>,
+}
+
+/// A setting from the environment, or its default
+fn setting(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<usize>()
+            .unwrap_or_else(|_| panic!("{} must be a number", name)),
+        Err(_) => default,
+    }
+}
+
+/// Start the server on a free port of the loopback interface. Returns the process and the address it listens on
+fn start_server() -> io::Result<(Child, SocketAddr)> {
+    let mut server: Child = Command::new(env!("CARGO_BIN_EXE_rust-irc"))
+        .args(["s", "--headless", "--bind", "127.0.0.1", "--port", "0"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout: ChildStdout = server.stdout.take().expect("stdout is piped");
+    let mut lines: io::Lines<BufReader<ChildStdout>> = BufReader::new(stdout).lines();
+    let mut addr: Option<SocketAddr> = None;
+    for line in lines.by_ref() {
+        if let Some(listening) = line?.strip_prefix("Server listening on ") {
+            addr = listening.parse::<SocketAddr>().ok();
+            break;
+        }
+    }
+    // The server logs every connection, and would stall if nothing read the log
+    thread::spawn(move || lines.for_each(drop));
+    match addr {
+        Some(addr) => Ok((server, addr)),
+        None => {
+            let _ = server.kill();
+            Err(io::Error::other("the server did not start listening"))
+        }
+    }
+}
+
+/// Connect, complete the version handshake, register a nickname and join a room
+async fn connect(addr: SocketAddr, nick: &str, room: &str) -> io::Result<(OwnedWriteHalf, Reader)> {
+    let (reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+    let mut reader: Reader = AsyncFrameReader::new(reader);
+    let join_server: Message = Message::JoinServer {
+        version: PROTOCOL_VERSION,
+        capabilities: vec![],
+    };
+    let register: Message = Message::RegisterNick {
+        nick: nick.to_string(),
+        password: None,
+    };
+    send_async(&mut writer, &join_server).await?;
+    send_async(&mut writer, &register).await?;
+    let join_room: Message = Message::JoinRoom {
+        room: room.to_string(),
+    };
+    send_async(&mut writer, &join_room).await?;
+    loop {
+        match read_message_async(&mut reader).await {
+            Ok(Message::ServerInfo { .. }) | Ok(Message::ResponseOk) => {}
+            Ok(Message::Response(_)) => return Ok((writer, reader)),
+            Ok(other) => return Err(io::Error::other(format!("{} refused: {:?}", nick, other))),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// Read messages until the connection closes, answering keep alive probes like a real client when given the writer.
+/// Chatters keep their writer for sending, and are never idle long enough to be probed.
+/// Room messages carry the time they were sent, to measure how long delivery took
+async fn listen(
+    mut writer: Option<OwnedWriteHalf>,
+    mut reader: Reader,
+    epoch: Instant,
+    stats: Arc<Stats>,
+) {
+    loop {
+        match read_message_async(&mut reader).await {
+            Ok(Message::KeepAlive) => {
+                if let Some(writer) = writer.as_mut() {
+                    if send_async(writer, &Message::ResponseOk).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Ok(Message::MessageRoom { text, .. }) => {
+                let sent: Option<u64> = text
+                    .split(' ')
+                    .next()
+                    .and_then(|micros: &str| micros.parse::<u64>().ok());
+                if let Some(sent) = sent {
+                    let now: u64 = epoch.elapsed().as_micros() as u64;
+                    stats
+                        .latencies
+                        .lock()
+                        .unwrap()
+                        .push(now.saturating_sub(sent));
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    stats.dropped.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Send room messages at a steady rate until the deadline
+async fn chatter(
+    mut writer: OwnedWriteHalf,
+    nick: String,
+    room: String,
+    rate: usize,
+    until: Instant,
+    epoch: Instant,
+    stats: Arc<Stats>,
+) -> OwnedWriteHalf {
+    let mut ticks: tokio::time::Interval =
+        tokio::time::interval(Duration::from_secs_f64(1.0 / rate as f64));
+    while Instant::now() < until {
+        ticks.tick().await;
+        let message: Message = Message::MessageRoom {
+            room: room.to_string(),
+            sender: nick.to_string(),
+            text: format!("{} the build is green", epoch.elapsed().as_micros()),
+        };
+        if send_async(&mut writer, &message).await.is_err() {
+            break;
+        }
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+    writer
+}
+
+/// The resident memory and thread count of a process, where /proc can tell
+fn process_usage(pid: u32) -> Option<(String, String)> {
+    let status: String = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let field = |name: &str| -> Option<String> {
+        status
+            .lines()
+            .find_map(|line: &str| line.strip_prefix(name))
+            .map(|value: &str| value.trim().to_string())
+    };
+    Some((field("VmRSS:")?, field("Threads:")?))
+}
+
+fn percentile(sorted: &[u64], fraction: f64) -> f64 {
+    let index: usize = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index] as f64 / 1000.0
+}
+
+#[tokio::main]
+async fn main() {
+    let idle: usize = setting("LOAD_IDLE", 5000);
+    let chatters: usize = setting("LOAD_CHATTERS", 50);
+    let rooms: usize = setting("LOAD_ROOMS", 10).max(1);
+    let rate: usize = setting("LOAD_RATE", 5).max(1);
+    let seconds: usize = setting("LOAD_SECONDS", 10);
+
+    let (mut server, addr) = start_server().expect("failed to start the server");
+    println!("Server listening on {}", addr);
+    let stats: Arc<Stats> = Arc::new(Stats::default());
+    let epoch: Instant = Instant::now();
+
+    let connecting: Arc<Semaphore> = Arc::new(Semaphore::new(CONNECTING_AT_ONCE));
+    let started: Instant = Instant::now();
+    let mut setups: Vec<tokio::task::JoinHandle<()>> = vec![];
+    for i in 0..idle {
+        let (stats, connecting) = (Arc::clone(&stats), Arc::clone(&connecting));
+        setups.push(tokio::spawn(async move {
+            let permit = connecting.acquire_owned().await.unwrap();
+            let lounge: String = format!("lounge{}", i / LOUNGE_SIZE);
+            match connect(addr, &format!("idle{}", i), &lounge).await {
+                Ok((writer, reader)) => {
+                    drop(permit);
+                    stats.connected.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(listen(Some(writer), reader, epoch, stats));
+                }
+                Err(_) => {
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }));
+    }
+    for setup in setups {
+        let _ = setup.await;
+    }
+    println!(
+        "Idle clients: {} connected in {:.2}s, {} failed",
+        stats.connected.load(Ordering::Relaxed),
+        started.elapsed().as_secs_f64(),
+        stats.failed.load(Ordering::Relaxed)
+    );
+
+    let mut members: Vec<usize> = vec![0; rooms];
+    let mut speakers: Vec<(OwnedWriteHalf, String, String)> = vec![];
+    for i in 0..chatters {
+        let (nick, room) = (format!("chatter{}", i), format!("room{}", i % rooms));
+        match connect(addr, &nick, &room).await {
+            Ok((writer, reader)) => {
+                members[i % rooms] += 1;
+                tokio::spawn(listen(None, reader, epoch, Arc::clone(&stats)));
+                speakers.push((writer, nick, room));
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    // Every message reaches the other members of its room
+    let fan_out: Vec<usize> = (0..chatters)
+        .map(|i: usize| members[i % rooms] - 1)
+        .collect();
+
+    let until: Instant = Instant::now() + Duration::from_secs(seconds as u64);
+    let mut talking: Vec<tokio::task::JoinHandle<OwnedWriteHalf>> = vec![];
+    for (writer, nick, room) in speakers {
+        let stats: Arc<Stats> = Arc::clone(&stats);
+        talking.push(tokio::spawn(chatter(
+            writer, nick, room, rate, until, epoch, stats,
+        )));
+    }
+    let mut writers: Vec<OwnedWriteHalf> = vec![];
+    for talker in talking {
+        writers.push(talker.await.unwrap());
+    }
+    // Let the last messages arrive
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let sent: usize = stats.sent.load(Ordering::Relaxed);
+    let expected: usize = sent / chatters.max(1) * fan_out.iter().sum::<usize>();
+    let mut latencies: Vec<u64> = stats.latencies.lock().unwrap().clone();
+    latencies.sort_unstable();
+    println!(
+        "Chatter: {} clients in {} rooms, {} messages a second each for {}s",
+        chatters, rooms, rate, seconds
+    );
+    println!(
+        "Messages: {} sent, {} delivered of about {} expected",
+        sent,
+        latencies.len(),
+        expected
+    );
+    if !latencies.is_empty() {
+        println!(
+            "Delivery latency: p50 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+            percentile(&latencies, 0.5),
+            percentile(&latencies, 0.99),
+            percentile(&latencies, 1.0)
+        );
+    }
+    println!(
+        "Disconnected by the server during the run: {}",
+        stats.dropped.load(Ordering::Relaxed)
+    );
+    if let Some((memory, threads)) = process_usage(server.id()) {
+        println!("Server: {} threads, {} resident", threads, memory);
+    }
+    drop(writers);
+    let _ = server.kill();
+    let _ = server.wait();
+}
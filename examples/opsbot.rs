@@ -0,0 +1,142 @@
+//! An example bot for an operations room: it announces deploys and says who is on call.
+//!
+//! Run it with `cargo run --example opsbot -- --host chat.local --join ops --oncall alice,bob,carol`.
+//! Options: `--host [host]` server, with or without a port (default localhost), `--nick [nickname]` (default opsbot),
+//! `--join [room]` which may be given more than once (default ops),
+//! `--notify [room]` where deploys are announced (default the first room joined),
+//! and `--oncall [nick,nick,...]` the on-call rotation, which moves to the next person every week.
+//! If the bot's nickname is registered, its password is read from the `RUST_IRC_PASSWORD` environment variable.
+
+use std::env;
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rust_irc::bot::{Bot, Context, Regex};
+use rust_irc::client::Options;
+
+const HELP: &str = "!deploy [service] [environment] to announce a deploy, !oncall to see who is on call, !help for this";
+const WEEK_SECS: u64 = 7 * 24 * 60 * 60;
+/// Password of the bot's nickname, if it is registered
+const PASSWORD_ENV: &str = "RUST_IRC_PASSWORD";
+
+struct Settings {
+    host: String,
+    nick: String,
+    password: Option<String>,
+    rooms: Vec<String>,
+    notify: Option<String>,
+    oncall: Vec<String>,
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("{}", problem);
+    eprintln!("Usage: opsbot [--host host] [--nick nickname] [--join room]... [--notify room] [--oncall nick,nick,...]");
+    exit(1)
+}
+
+fn parse_args() -> Settings {
+    let mut settings: Settings = Settings {
+        host: "localhost".to_string(),
+        nick: "opsbot".to_string(),
+        password: env::var(PASSWORD_ENV).ok(),
+        rooms: vec![],
+        notify: None,
+        oncall: vec![],
+    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: std::slice::Iter<'_, String> = args.iter();
+    while let Some(arg) = args.next() {
+        let value: String = match args.next() {
+            Some(value) => value.to_string(),
+            None => usage(&format!("Missing value for {}", arg)),
+        };
+        match arg.as_str() {
+            "--host" => settings.host = value,
+            "--nick" => settings.nick = value,
+            "--join" => settings.rooms.push(value),
+            "--notify" => settings.notify = Some(value),
+            "--oncall" => {
+                settings.oncall = value
+                    .split(',')
+                    .map(|nick: &str| nick.trim().to_string())
+                    .filter(|nick: &String| !nick.is_empty())
+                    .collect()
+            }
+            other => usage(&format!("Unknown option {}", other)),
+        }
+    }
+    if settings.rooms.is_empty() {
+        settings.rooms.push("ops".to_string());
+    }
+    settings
+}
+
+/// Who is on call this week and next, going round the rotation once a week
+fn on_call(rotation: &[String]) -> String {
+    if rotation.is_empty() {
+        return "Nobody is on call, start me with --oncall".to_string();
+    }
+    let now: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since: Duration| since.as_secs())
+        .unwrap_or(0);
+    let week: usize = (now / WEEK_SECS) as usize;
+    format!(
+        "On call this week: {}, next week: {}",
+        rotation[week % rotation.len()],
+        rotation[(week + 1) % rotation.len()]
+    )
+}
+
+fn main() {
+    let settings: Settings = parse_args();
+    let notify: String = settings
+        .notify
+        .clone()
+        .unwrap_or_else(|| settings.rooms[0].to_string());
+    let options: Options = Options {
+        password: settings.password.clone(),
+        tls: None,
+        timeout: None,
+    };
+
+    let mut bot: Bot = Bot::new(&settings.host, &settings.nick).options(options);
+    for room in &settings.rooms {
+        bot = bot.join(room);
+    }
+    if !settings.rooms.contains(&notify) {
+        bot = bot.join(&notify);
+    }
+    let (rotation, paged): (Vec<String>, Vec<String>) =
+        (settings.oncall.clone(), settings.oncall.clone());
+    let result: rust_irc::Result<()> = bot
+        .command("help", |ctx: &Context| ctx.reply(HELP))
+        .command("oncall", move |ctx: &Context| {
+            ctx.reply(&on_call(&rotation))
+        })
+        .command("deploy", move |ctx: &Context| {
+            let mut words: std::str::SplitWhitespace<'_> = ctx.args.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some(service), Some(environment)) => {
+                    let text: String =
+                        format!("{} is deploying {} to {}", ctx.sender, service, environment);
+                    ctx.announce(&notify, &text)?;
+                    if ctx.room != notify {
+                        ctx.reply_to_sender(&format!("announced in {}", notify))?;
+                    }
+                    Ok(())
+                }
+                _ => ctx.reply_to_sender("usage: !deploy [service] [environment]"),
+            }
+        })
+        // Anyone mentioning an incident is told who to talk to
+        .pattern(
+            Regex::new(r"(?i)\b(incident|outage|paging)\b").unwrap(),
+            move |ctx: &Context| ctx.reply(&on_call(&paged)),
+        )
+        .run();
+    if let Err(e) = result {
+        eprintln!("{} could not connect: {}", settings.nick, e);
+        exit(1);
+    }
+}
@@ -0,0 +1,131 @@
+//! Nicknames registered with a password, which must be given to use the nickname.
+//!
+//! Only an Argon2 hash of each password is kept, in `accounts.txt` in the server's data directory
+//! as one `nickname hash` line per account.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+/// The file in the data directory which accounts are saved in
+const ACCOUNTS_FILE: &str = "accounts.txt";
+
+#[derive(Debug, Default)]
+pub struct Accounts {
+    /// The password hash of each registered nickname
+    hashes: HashMap<String, String>,
+    /// Where accounts are saved. None to keep them only until the server stops
+    path: Option<PathBuf>,
+}
+
+impl Accounts {
+    /// Read the accounts saved in the data directory, which are saved there from now on
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path: PathBuf = dir.join(ACCOUNTS_FILE);
+        let text: String = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let mut hashes: HashMap<String, String> = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_once(' ') {
+                Some((nick, hash)) if !nick.is_empty() && PasswordHash::new(hash).is_ok() => {
+                    hashes.insert(nick.to_string(), hash.to_string());
+                }
+                _ => return Err(format!("{}: line {}", path.display(), number + 1)),
+            }
+        }
+        Ok(Accounts {
+            hashes,
+            path: Some(path),
+        })
+    }
+
+    /// The password hash of a nickname, if it is registered
+    pub fn hash_of(&self, nick: &str) -> Option<String> {
+        self.hashes.get(nick).cloned()
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.hashes.contains_key(nick)
+    }
+
+    /// Register a nickname, or change its password, then save every account.
+    /// A complete copy is renamed over the old file, so a crash cannot leave it half written
+    pub fn set(&mut self, nick: &str, hash: String) -> io::Result<()> {
+        self.hashes.insert(nick.to_string(), hash);
+        let path: &Path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut nicks: Vec<&String> = self.hashes.keys().collect();
+        nicks.sort();
+        let mut text: String = String::new();
+        for nick in nicks {
+            text.push_str(&format!("{} {}\n", nick, self.hashes[nick]));
+        }
+        let partial: PathBuf = path.with_extension("txt.tmp");
+        fs::write(&partial, text)?;
+        fs::rename(&partial, path)
+    }
+}
+
+/// Whether a nickname may be registered, since each account must fit on one line of the accounts file
+pub fn valid_nick(nick: &str) -> bool {
+    !nick.is_empty()
+        && !nick
+            .chars()
+            .any(|c: char| c.is_whitespace() || c.is_control())
+}
+
+/// Hash a password with a new random salt. This is deliberately slow, so never call it holding the server lock
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt: SaltString = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash: PasswordHash<'_>| hash.to_string())
+        .map_err(|e: argon2::password_hash::Error| e.to_string())
+}
+
+/// Whether a password matches a hash made by [`hash_password`]. Also slow, like hashing
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn test_accounts() {
+    let hash: String = hash_password("hunter2 with spaces").unwrap();
+    assert!(verify_password("hunter2 with spaces", &hash));
+    assert!(!verify_password("hunter3", &hash));
+    assert!(!verify_password("hunter2", "not a hash"));
+    assert_ne!(hash, hash_password("hunter2 with spaces").unwrap());
+
+    let dir: PathBuf =
+        std::env::temp_dir().join(format!("rust-irc-accounts-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut accounts: Accounts = Accounts::load(&dir).unwrap();
+    assert!(!accounts.is_registered("westy"));
+    accounts.set("westy", hash.to_string()).unwrap();
+    let reloaded: Accounts = Accounts::load(&dir).unwrap();
+    assert_eq!(reloaded.hash_of("westy"), Some(hash));
+    fs::write(dir.join(ACCOUNTS_FILE), "westy plaintext\n").unwrap();
+    assert!(Accounts::load(&dir).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(valid_nick("westy"));
+    assert!(!valid_nick("we\nsty"));
+}
@@ -0,0 +1,112 @@
+//! The server application, which runs a [`rust_irc::server::Server`] with an admin menu on stdin,
+//! or without one until SIGTERM or SIGINT when headless.
+
+use prompted::input;
+use rust_irc::server::{Config, Event, Handle, Server};
+use rust_irc::{clear, read_line};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// What to run the server with, normally taken from the command line
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Run without the stdin admin menu, until SIGTERM or SIGINT
+    pub headless: bool,
+    pub config: Config,
+}
+
+/// Entrypoint for the server
+/// Main thread -> Main Menu, or waiting for SIGTERM/SIGINT when headless
+/// The server itself runs on its own threads, and the menu acts on it through its handle
+pub fn start(options: Options) {
+    let server: Server = Server::with_config(options.config);
+    if options.headless {
+        println!("Running headless");
+        if let Err(e) = server.run() {
+            eprintln!("Failed to start the server: {}", e);
+        }
+        return;
+    }
+
+    clear();
+    // Every event waits for this lock, so holding it stops the server's threads one event at a time
+    let freeze: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+    let freeze_inner: Arc<Mutex<()>> = Arc::clone(&freeze);
+    let server: Server = server.on_event(move |_: &Event| {
+        drop(freeze_inner.lock().unwrap_or_else(PoisonError::into_inner));
+    });
+    let handle: Arc<Handle> = match server.spawn() {
+        Ok(handle) => Arc::new(handle),
+        Err(e) => {
+            eprintln!("Failed to start the server: {}. Try again", e);
+            return;
+        }
+    };
+
+    // SIGTERM/SIGINT stop the server the same way the "0: Quit Server" option does
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = stop_tx.send(());
+    }) {
+        eprintln!("Failed to install signal handler: {}", e);
+    }
+    let handle_signal: Arc<Handle> = Arc::clone(&handle);
+    thread::spawn(move || {
+        if stop_rx.recv().is_ok() {
+            handle_signal.shutdown();
+            std::process::exit(0);
+        }
+    });
+
+    // Main Menu Loop on the main thread.
+    loop {
+        println!("0: Quit Server");
+        println!("1: list connected users");
+        println!("2: list rooms");
+        println!("3: Broadcast message to all");
+        println!("4: Freeze server by holding a lock (for testing)");
+        let inp: String = match read_line() {
+            Some(line) => line,
+            None => {
+                // No stdin to read the menu from, keep serving until signalled
+                println!("Admin menu closed, send SIGTERM or SIGINT to stop");
+                loop {
+                    thread::park();
+                }
+            }
+        };
+        match inp.trim().parse::<u8>() {
+            Ok(num) => match num {
+                0 => {
+                    handle.shutdown();
+                    break;
+                }
+                1 => println!("Users: {:?}", handle.users()),
+                2 => {
+                    println!("Rooms:");
+                    for room in handle.rooms() {
+                        let members: Vec<String> = handle.members(&room).unwrap_or_default();
+                        println!("  {}: {}", room, members.join(" "));
+                    }
+                }
+                3 => {
+                    let inp2 = input!("Enter message: ");
+                    handle.broadcast(&inp2);
+                }
+                // For testing that clients notice a server which has stopped responding
+                4 => {
+                    let _frozen: MutexGuard<'_, ()> =
+                        freeze.lock().unwrap_or_else(PoisonError::into_inner);
+                    println!("Server frozen, send SIGKILL to stop");
+                    loop {
+                        thread::park();
+                    }
+                }
+                _ => println!("Invalid Input"),
+            },
+            Err(_) => {
+                println!("Invalid input");
+            }
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! A framework for bots which sit in rooms and answer messages, built on [`crate::client::Client`].
+//!
+//! Handlers are registered for `!command` messages, or for room messages matching a regular expression,
+//! and answer through the [`Context`] they are called with. [`Bot::run`] connects, joins the bot's rooms
+//! and dispatches every room message to the matching handlers. When the connection is lost it reconnects,
+//! waiting longer after each failed attempt, and joins the same rooms again. A server which goes quiet is
+//! probed with `KEEP_ALIVE`, and counts as lost if it still says nothing, so a half-open connection is noticed too.
+//!
+//! ```no_run
+//! use rust_irc::bot::{Bot, Context, Regex};
+//!
+//! Bot::new("chat.local", "opsbot")
+//!     .join("ops")
+//!     .command("ping", |ctx: &Context| ctx.reply("pong"))
+//!     .pattern(Regex::new(r"deployed (\S+)").unwrap(), |ctx: &Context| {
+//!         ctx.reply(&format!("Noted, {} is out", ctx.group(1).unwrap_or("it")))
+//!     })
+//!     .run()?;
+//! # Ok::<(), rust_irc::Error>(())
+//! ```
+
+use std::cell::{Cell, RefCell, RefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use regex::Regex;
+
+use crate::client::{Client, Options};
+use crate::protocol::{ErrorCode, Message};
+use crate::{Error, Result};
+
+/// Prefix of a command, such as `!deploy`
+const DEFAULT_PREFIX: &str = "!";
+/// How long to wait before the first attempt to reconnect
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest wait between attempts to reconnect, however many have failed
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// A server silent this long is sent a `KEEP_ALIVE` probe
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(10);
+/// A server silent this long is given up on and reconnected to
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A function called with a room message which matched its trigger
+type Action = Box<dyn FnMut(&Context) -> Result<()> + Send>;
+
+/// Which room messages a handler is called for
+enum Trigger {
+    /// Messages starting with the prefix and then this command name
+    Command(String),
+    Pattern(Regex),
+}
+
+struct Handler {
+    trigger: Trigger,
+    action: Action,
+}
+
+/// A bot, configured with the rooms it joins and the handlers for messages in them
+pub struct Bot {
+    host: String,
+    nick: String,
+    options: Options,
+    prefix: String,
+    reconnect_delay: Duration,
+    keepalive: Duration,
+    timeout: Duration,
+    rooms: Vec<String>,
+    handlers: Vec<Handler>,
+}
+
+/// A room message which matched a handler, and the connection to answer it on
+pub struct Context<'a> {
+    client: &'a Client,
+    /// Rooms to join again after reconnecting
+    rooms: &'a RefCell<Vec<String>>,
+    stopping: &'a Cell<bool>,
+    pub room: String,
+    pub sender: String,
+    /// The whole message
+    pub text: String,
+    /// For a command, the rest of the message after its name. For a pattern, the whole message
+    pub args: String,
+    /// For a pattern, its capture groups, starting with the whole match. Empty for a command
+    pub captures: Vec<Option<String>>,
+}
+
+impl Bot {
+    /// A bot which connects to `host`, which may include a port, as `nick`
+    pub fn new(host: &str, nick: &str) -> Self {
+        Bot {
+            host: host.to_string(),
+            nick: nick.to_string(),
+            options: Options::default(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            keepalive: DEFAULT_KEEPALIVE,
+            timeout: DEFAULT_TIMEOUT,
+            rooms: vec![],
+            handlers: vec![],
+        }
+    }
+
+    /// How to connect, such as with a password or over TLS
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// What commands start with, `!` by default
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// How long to wait before the first attempt to reconnect. Each failed attempt doubles it, up to a minute
+    pub fn reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    /// Probe the server with a keep alive once it has been silent for `keepalive`, 10 seconds by default,
+    /// and reconnect once it has been silent for `timeout`, 30 seconds by default.
+    /// Connecting gives up after `timeout` too, unless the bot's [`Options`] set their own
+    pub fn keepalive(mut self, keepalive: Duration, timeout: Duration) -> Self {
+        self.keepalive = keepalive;
+        self.timeout = timeout;
+        self
+    }
+
+    /// A room to join on connecting, and again on every reconnect
+    pub fn join(mut self, room: &str) -> Self {
+        if !self.rooms.iter().any(|r: &String| r == room) {
+            self.rooms.push(room.to_string());
+        }
+        self
+    }
+
+    /// Call `action` for room messages starting with the prefix and `name`, such as `!deploy web prod`
+    pub fn command<F>(mut self, name: &str, action: F) -> Self
+    where
+        F: FnMut(&Context) -> Result<()> + Send + 'static,
+    {
+        self.handlers.push(Handler {
+            trigger: Trigger::Command(name.to_string()),
+            action: Box::new(action),
+        });
+        self
+    }
+
+    /// Call `action` for room messages matching `pattern` anywhere in their text
+    pub fn pattern<F>(mut self, pattern: Regex, action: F) -> Self
+    where
+        F: FnMut(&Context) -> Result<()> + Send + 'static,
+    {
+        self.handlers.push(Handler {
+            trigger: Trigger::Pattern(pattern),
+            action: Box::new(action),
+        });
+        self
+    }
+
+    /// Connect and answer room messages until a handler calls [`Context::stop`].
+    /// A lost connection is reconnected, and a failed attempt is retried after a growing delay.
+    /// Returns an error only if the bot is refused in a way retrying cannot fix, such as a wrong password or an invalid nickname
+    pub fn run(mut self) -> Result<()> {
+        let rooms: RefCell<Vec<String>> = RefCell::new(self.rooms.clone());
+        let stopping: Cell<bool> = Cell::new(false);
+        let mut delay: Duration = self.reconnect_delay;
+        // A server which accepts the connection but never registers the bot is noticed like a silent one
+        self.options.timeout = self.options.timeout.or(Some(self.timeout));
+        loop {
+            let client: Client = match Client::connect(&self.host, &self.nick, &self.options) {
+                Ok(client) => client,
+                Err(Error::Refused(code))
+                    if !matches!(code, ErrorCode::NicknameCollision | ErrorCode::ServerFull) =>
+                {
+                    return Err(Error::Refused(code));
+                }
+                Err(Error::Invalid(reason)) => return Err(Error::Invalid(reason)),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to connect to {}: {}. Retrying in {}s",
+                        self.host,
+                        e,
+                        delay.as_secs_f32()
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            delay = self.reconnect_delay;
+            for room in rooms.borrow().iter() {
+                // A connection lost already shows up in the incoming messages, and is reconnected from there
+                let _ = client.join(room);
+            }
+            let timed_out: AtomicBool = AtomicBool::new(false);
+            let (keepalive, timeout): (Duration, Duration) = (self.keepalive, self.timeout);
+            let result: Result<()> = thread::scope(|scope: &thread::Scope<'_, '_>| {
+                let (done, finished): (Sender<()>, Receiver<()>) = mpsc::channel();
+                scope.spawn(|| watch(&client, finished, keepalive, timeout, &timed_out));
+                let result: Result<()> = client.run(|client: &Client, message: Message| {
+                    if let Message::MessageRoom { room, sender, text } = message {
+                        if sender != client.nick() {
+                            self.dispatch(client, &rooms, &stopping, room, sender, text);
+                        }
+                    }
+                    Ok(())
+                });
+                drop(done);
+                result
+            });
+            if stopping.get() {
+                return Ok(());
+            }
+            match result {
+                _ if timed_out.load(Ordering::Relaxed) => eprintln!(
+                    "{} has not answered for {}s. Reconnecting",
+                    self.host,
+                    timeout.as_secs_f32()
+                ),
+                Ok(()) => eprintln!("{} closed the connection. Reconnecting", self.host),
+                Err(e) => eprintln!("Lost the connection to {}: {}. Reconnecting", self.host, e),
+            }
+            // So that a server which drops the bot straight after it connects is not retried in a tight loop
+            thread::sleep(delay);
+        }
+    }
+
+    /// Call every handler whose trigger matches the message
+    fn dispatch(
+        &mut self,
+        client: &Client,
+        rooms: &RefCell<Vec<String>>,
+        stopping: &Cell<bool>,
+        room: String,
+        sender: String,
+        text: String,
+    ) {
+        // The command name, and the rest of the message, if this is a command
+        let command: Option<(&str, &str)> = text.strip_prefix(&self.prefix).map(|rest: &str| {
+            let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+            (name, args.trim())
+        });
+        for handler in self.handlers.iter_mut() {
+            let (args, captures): (String, Vec<Option<String>>) = match &handler.trigger {
+                Trigger::Command(name) => match command {
+                    Some((called, args)) if called == name => (args.to_string(), vec![]),
+                    _ => continue,
+                },
+                Trigger::Pattern(pattern) => match pattern.captures(&text) {
+                    Some(found) => {
+                        let groups: Vec<Option<String>> = found
+                            .iter()
+                            .map(|group| group.map(|g: regex::Match| g.as_str().to_string()))
+                            .collect();
+                        (text.to_string(), groups)
+                    }
+                    None => continue,
+                },
+            };
+            let ctx: Context = Context {
+                client,
+                rooms,
+                stopping,
+                room: room.to_string(),
+                sender: sender.to_string(),
+                text: text.to_string(),
+                args,
+                captures,
+            };
+            if let Err(e) = (handler.action)(&ctx) {
+                eprintln!("Handler failed on {:?} in {}: {}", text, room, e);
+            }
+        }
+    }
+}
+
+/// Until `finished` is dropped, probe the server with a keep alive whenever it has been silent for `keepalive`,
+/// and close the connection once it has been silent for `timeout`, which ends [`Client::run`]
+fn watch(
+    client: &Client,
+    finished: Receiver<()>,
+    keepalive: Duration,
+    timeout: Duration,
+    timed_out: &AtomicBool,
+) {
+    while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(keepalive / 2) {
+        let silent: Duration = Instant::now().duration_since(client.last_heard());
+        if silent > timeout {
+            timed_out.store(true, Ordering::Relaxed);
+            let _ = client.quit();
+            return;
+        } else if silent >= keepalive {
+            // A connection lost already shows up in the incoming messages
+            let _ = client.send(&Message::KeepAlive);
+        }
+    }
+}
+
+impl Context<'_> {
+    /// The connection, to send anything the other helpers do not cover
+    pub fn client(&self) -> &Client {
+        self.client
+    }
+
+    /// A capture group of the pattern which matched, if it matched anything. Group 0 is the whole match
+    pub fn group(&self, index: usize) -> Option<&str> {
+        self.captures.get(index)?.as_deref()
+    }
+
+    /// Send a message to the room the message came from
+    pub fn reply(&self, text: &str) -> Result<()> {
+        self.client.message_room(&self.room, text)
+    }
+
+    /// Send a message to the room the message came from, addressed to its sender
+    pub fn reply_to_sender(&self, text: &str) -> Result<()> {
+        self.reply(&format!("{}: {}", self.sender, text))
+    }
+
+    /// Send a private message to the sender
+    pub fn whisper(&self, text: &str) -> Result<()> {
+        self.client.private_message(&self.sender, text)
+    }
+
+    /// Send a message to another room the bot is in, such as a notifications room
+    pub fn announce(&self, room: &str, text: &str) -> Result<()> {
+        self.client.message_room(room, text)
+    }
+
+    /// Join a room, and join it again after reconnecting
+    pub fn join(&self, room: &str) -> Result<()> {
+        let mut rooms: RefMut<'_, Vec<String>> = self.rooms.borrow_mut();
+        if !rooms.iter().any(|r: &String| r == room) {
+            rooms.push(room.to_string());
+        }
+        self.client.join(room)
+    }
+
+    /// Leave a room, and stay out of it after reconnecting
+    pub fn leave(&self, room: &str) -> Result<()> {
+        self.rooms.borrow_mut().retain(|r: &String| r != room);
+        self.client.leave(room)
+    }
+
+    /// Quit the server, after which [`Bot::run`] returns
+    pub fn stop(&self) -> Result<()> {
+        self.stopping.set(true);
+        self.client.quit()
+    }
+}
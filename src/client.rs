@@ -1,251 +1,369 @@
-use prompted::input;
-use rust_irc::buf_helpers::{one_op_buf, one_param_buf, two_param_buf};
-use rust_irc::{clear, codes, DEFAULT_PORT};
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+//! A client for bots, tools and front ends, which connects to a server, registers a nickname and then
+//! sends requests and receives messages without touching stdin or stdout.
+//!
+//! [`Client::connect`] returns once the nickname is registered, or with the error the server refused it with.
+//! A thread then reads everything the server sends. It answers the server's `KEEP_ALIVE` probes itself,
+//! and hands every other message to [`Client::incoming`] or [`Client::run`] in the order it arrived.
+//! Requests are sent without waiting for their answers, which arrive as incoming messages like any other.
+//!
+//! ```no_run
+//! use rust_irc::client::{Client, Options};
+//! use rust_irc::protocol::Message;
+//!
+//! let client: Client = Client::connect("chat.local", "echo", &Options::default())?;
+//! client.join("cat")?;
+//! client.run(|client: &Client, message: Message| match message {
+//!     Message::MessageRoom { room, sender, text } if sender != client.nick() => {
+//!         client.message_room(&room, &format!("{} said {}", sender, text))
+//!     }
+//!     _ => Ok(()),
+//! })?;
+//! # Ok::<(), rust_irc::Error>(())
+//! ```
+
+use std::io;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::thread;
 use std::time::{Duration, Instant};
 
-const DEFAULT_HOST: &str = "localhost";
+use crate::accounts;
+use crate::framing::{write_frame, FrameReader, MAX_FRAME_LEN};
+use crate::protocol::{read_message, send, Message};
+use crate::tls::{self, TlsStream, Verify};
+use crate::transport::Stream;
+use crate::{capabilities, host_with_port, Error, Result, DEFAULT_PORT, PROTOCOL_VERSION};
 
-fn read_messages(mut stream: TcpStream, nick: &str, timestamp: &mut Arc<Mutex<Instant>>) {
-    let mut buffer: [u8; 1024] = [0; 1024];
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => {
-                println!("Server closed the connection. Shutting down client");
-                std::process::exit(0);
-            }
-            Ok(size) => {
-                let mut lock: std::sync::MutexGuard<'_, Instant> = timestamp.lock().unwrap();
-                *lock = Instant::now();
-                let msg_bytes: &[u8] = &buffer[..size];
-                process_message(msg_bytes, nick);
-            }
-            Err(_) => {
-                break;
-            }
-        }
+/// Optional protocol features this client supports, announced in the version handshake
+const CLIENT_CAPABILITIES: &[&str] = &[
+    capabilities::PRIVATE_MESSAGES,
+    capabilities::OPERATORS,
+    capabilities::TOPICS,
+    capabilities::NICK_CHANGES,
+    capabilities::MEMBERSHIP_EVENTS,
+    capabilities::HISTORY,
+    capabilities::PERSISTENT_ROOMS,
+    capabilities::ACCOUNTS,
+];
+
+/// How long to wait for a server to accept the connection and register us, unless the options say otherwise
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How to connect, beyond the host and nickname
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Password for a nickname registered as an account
+    pub password: Option<String>,
+    /// Connect over TLS, verifying the server this way. Plain TCP when None
+    pub tls: Option<Verify>,
+    /// How long connecting, the TLS handshake and each answer while registering may take. None for 30 seconds
+    pub timeout: Option<Duration>,
+}
+
+/// What the server said about itself in the version handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: u16,
+    pub capabilities: Vec<String>,
+}
+
+/// State shared with the thread reading the connection
+#[derive(Debug)]
+struct Shared {
+    writer: Mutex<Stream>,
+    /// Our nickname, which changes once the server accepts a nickname change
+    nick: Mutex<String>,
+    /// When the server last sent anything
+    last_heard: Mutex<Instant>,
+}
+
+/// A connection to a server with a registered nickname.
+/// Every method takes `&self`, so one thread can send while another reads through [`Client::incoming`]
+#[derive(Debug)]
+pub struct Client {
+    shared: Arc<Shared>,
+    incoming: Mutex<Receiver<Result<Message>>>,
+    server: ServerInfo,
+}
+
+/// Messages from the server, as returned by [`Client::incoming`]
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    client: &'a Client,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A nickname or room name must be one word the server can accept, so that a bad one is caught before it is sent
+fn check_name(what: &str, name: &str) -> Result<()> {
+    if accounts::valid_nick(name) {
+        Ok(())
+    } else if name.is_empty() {
+        Err(Error::Invalid(format!("the {} is empty", what)))
+    } else {
+        Err(Error::Invalid(format!(
+            "the {} {:?} contains whitespace or control characters",
+            what, name
+        )))
     }
 }
 
-fn process_message(msg_bytes: &[u8], nick: &str) {
-    match msg_bytes[0] {
-        codes::ERROR => match msg_bytes[1] {
-            codes::error::INVALID_ROOM => {
-                eprintln!("Operation Performed on an invalid room. Try again");
+impl Client {
+    /// Connect to `host`, which may include a port (otherwise the default port), and register `nick`
+    pub fn connect(host: &str, nick: &str, options: &Options) -> Result<Client> {
+        check_name("nickname", nick)?;
+        let host: String = host_with_port(host, DEFAULT_PORT);
+        let timeout: Duration = options.timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let socket: TcpStream = open(&host, timeout)?;
+        // Cleared by `over` once registered, so that a server which never answers is not waited for forever
+        socket.set_read_timeout(Some(timeout))?;
+        let stream: Stream = match &options.tls {
+            Some(verify) => {
+                let config: Arc<rustls::ClientConfig> = tls::client_config(verify)?;
+                Box::new(TlsStream::connect(socket, tls::host_name(&host), config)?)
             }
-            codes::error::NICKNAME_COLLISION => {
-                eprintln!("Nickname already in use on server. Connect again with a different one");
-                std::process::exit(1);
-            }
-            codes::error::SERVER_FULL => {
-                eprintln!("Server is full. Try again later");
-            }
-            codes::error::NOT_IN_ROOM => {
-                eprintln!("Cannot interact with a room you have not joined. Use /join [room].")
-            }
-            codes::error::EMPTY_ROOM => {
-                eprintln!("Room is Empty");
-            }
-            codes::error::ALREADY_IN_ROOM => {
-                eprintln!("You are already in that room");
-            }
-            _ => {
-                eprintln!("Error code: {:x?}", msg_bytes[1]);
-            }
-        },
+            None => Box::new(socket),
+        };
+        Client::over(stream, nick, options.password.clone())
+    }
 
-        codes::MESSAGE => {
-            let message: String =
-                String::from_utf8(msg_bytes[1..msg_bytes.len()].to_vec()).unwrap();
-            println!("[server]:{}", message);
-        }
-        codes::MESSAGE_ROOM => {
-            let params: String = String::from_utf8(msg_bytes[1..msg_bytes.len()].to_vec()).unwrap();
-            match params.split_once(' ') {
-                Some((room, remainder)) => match remainder.split_once(' ') {
-                    Some((user, msg)) => {
-                        if user != nick {
-                            println!("[{}]:[{}]: {}", room, user, msg);
-                        }
-                    }
-                    None => {
-                        eprintln!("Malformed message recieved: {}", params);
-                    }
-                },
-                None => {
-                    eprintln!("Malformed message recieved: {}", params);
-                }
-            }
-        }
+    /// Register `nick` over a connection which is already open, such as one end of [`crate::transport::duplex`].
+    /// A read timeout set on the stream limits how long registering waits for the server, and is cleared afterwards
+    pub fn over(mut stream: Stream, nick: &str, password: Option<String>) -> Result<Client> {
+        check_name("nickname", nick)?;
+        let mut reader: FrameReader<Stream> = FrameReader::new(stream.try_clone()?);
+        let handshake: Message = Message::JoinServer {
+            version: PROTOCOL_VERSION,
+            capabilities: CLIENT_CAPABILITIES
+                .iter()
+                .map(|c: &&str| c.to_string())
+                .collect(),
+        };
+        send(&mut stream, &handshake)?;
+        let register: Message = Message::RegisterNick {
+            nick: nick.to_string(),
+            password,
+        };
+        send(&mut stream, &register)?;
 
-        codes::RESPONSE_OK => {}
-        codes::RESPONSE => {
-            let message: String =
-                String::from_utf8(msg_bytes[1..msg_bytes.len()].to_vec()).unwrap();
-            println!("{}", message);
-        }
-        codes::QUIT => {
-            println!("Server has closed the connection. Stopping client");
-            std::process::exit(0);
-        }
-        _ => {
-            eprintln!("BAD RESPONSE = {:x?} ", msg_bytes[0]);
+        let server: ServerInfo = match read_message(&mut reader)? {
+            Message::ServerInfo {
+                version,
+                name,
+                capabilities,
+            } => ServerInfo {
+                name,
+                version,
+                capabilities,
+            },
+            Message::Error(code) => return Err(Error::Refused(code)),
+            other => return Err(unexpected(&other)),
+        };
+        match read_message(&mut reader)? {
+            Message::ResponseOk => {}
+            Message::Error(code) => return Err(Error::Refused(code)),
+            other => return Err(unexpected(&other)),
         }
+        stream.set_read_timeout(None)?;
+
+        let shared: Arc<Shared> = Arc::new(Shared {
+            writer: Mutex::new(stream),
+            nick: Mutex::new(nick.to_string()),
+            last_heard: Mutex::new(Instant::now()),
+        });
+        let (sender, receiver) = mpsc::channel::<Result<Message>>();
+        let shared_inner: Arc<Shared> = Arc::clone(&shared);
+        thread::spawn(move || read_messages(reader, &shared_inner, sender));
+        Ok(Client {
+            shared,
+            incoming: Mutex::new(receiver),
+            server,
+        })
     }
-}
 
-fn disconnect(stream: &mut TcpStream) {
-    stream.write_all(&[codes::QUIT]).unwrap();
-    stream.shutdown(std::net::Shutdown::Both).unwrap();
-}
+    /// Our nickname, as the server last confirmed it
+    pub fn nick(&self) -> String {
+        lock(&self.shared.nick).to_string()
+    }
 
-fn help() {
-    println!("Available commands:");
-    println!("/quit <- Disconnect and stop the client");
-    println!("/rooms <- List all of the rooms on the server");
-    println!("/users <- List all of the user connected to the server");
-    println!("/list [room-name] <- List all of the users in the given room");
-    println!("/join [room-name] <- Join the given room. Create the room if it does not exist");
-    println!(
-        "/leave [room-name] <- Leave the given room. Error if you are not already in the room"
-    );
-}
+    pub fn server(&self) -> &ServerInfo {
+        &self.server
+    }
 
-/// Client application: prompt user for nickname and host
-/// On a new thread, try to open a tcp connection to given host, and register nickname first
-/// After registring the nickname, main loop to take in, parse, and send user commands
-/// Also, spawn a new thread as a watchdog for possible unresponsive server
-pub fn start() {
-    clear();
-    println!("Starting the IRC client. No spaces allowed in nicknames or room names.");
-    let mut nick: String;
-    loop {
-        nick = input!("Enter your nickname : ");
-        if nick.contains(' ') {
-            println!("May not contain spaces . Try again");
-        } else if nick.is_empty() {
-            println!("May not be empty . Try again");
-        } else {
-            break;
+    /// When the server last sent anything, to notice a server which has stopped answering
+    pub fn last_heard(&self) -> Instant {
+        *lock(&self.shared.last_heard)
+    }
+
+    /// Send any message to the server. A message too long to fit in a frame is an [`Error::Invalid`]
+    pub fn send(&self, message: &Message) -> Result<()> {
+        let payload: Vec<u8> = message.encode();
+        if payload.len() > MAX_FRAME_LEN {
+            return Err(Error::Invalid(format!(
+                "message is longer than {} bytes",
+                MAX_FRAME_LEN
+            )));
         }
+        Ok(write_frame(&mut *lock(&self.shared.writer), &payload)?)
+    }
+
+    /// Join a room, creating it if it does not exist
+    pub fn join(&self, room: &str) -> Result<()> {
+        check_name("room name", room)?;
+        self.send(&Message::JoinRoom {
+            room: room.to_string(),
+        })
     }
 
-    let mut host: String = input!("Enter the server host (empty for {}): ", DEFAULT_HOST);
-    if host.is_empty() {
-        host = DEFAULT_HOST.to_owned();
+    pub fn leave(&self, room: &str) -> Result<()> {
+        check_name("room name", room)?;
+        self.send(&Message::LeaveRoom {
+            room: room.to_string(),
+        })
     }
-    host.push(':');
-    host.push_str(&DEFAULT_PORT.to_string());
-    if let Ok(mut stream) = TcpStream::connect(host.to_owned()) {
-        println!("Connected to {}.\n/help to see available commands", host);
 
-        //another stream for reading messages
-        let reader_clone: TcpStream = stream.try_clone().expect("Failed to clone stream");
-        let mut keepalive_clone = stream.try_clone().expect("failed to clone stream");
-        let nick_clone: String = nick.clone();
+    /// Send a message to the other members of a room we have joined
+    pub fn message_room(&self, room: &str, text: &str) -> Result<()> {
+        check_name("room name", room)?;
+        self.send(&Message::MessageRoom {
+            room: room.to_string(),
+            sender: self.nick(),
+            text: text.to_string(),
+        })
+    }
 
-        //timestamp for detecting unresponsive server
-        let timestamp: Arc<Mutex<Instant>> = Arc::new(Mutex::new(Instant::now()));
-        let mut timestamp_clone: Arc<Mutex<Instant>> = Arc::clone(&timestamp);
+    /// Send a message to the other members of every room we have joined
+    pub fn message_all(&self, text: &str) -> Result<()> {
+        self.send(&Message::Message {
+            text: text.to_string(),
+        })
+    }
 
-        thread::spawn(move || {
-            read_messages(reader_clone, &nick_clone, &mut timestamp_clone);
-        });
+    /// Send a message directly to another user
+    pub fn private_message(&self, nick: &str, text: &str) -> Result<()> {
+        check_name("nickname", nick)?;
+        self.send(&Message::PrivateMessage {
+            nick: nick.to_string(),
+            text: text.to_string(),
+        })
+    }
 
-        //watchdog to send keep_alive and stop client if server fails to respond
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_secs(5));
-            let lock: std::sync::MutexGuard<'_, Instant> = timestamp.lock().unwrap();
-            let now: Instant = Instant::now();
-            if now.duration_since(*lock) > Duration::from_secs(30) {
-                eprintln!("Server is unresponsive. Stopping client");
-                std::process::exit(1);
-            } else if now.duration_since(*lock) > Duration::from_secs(5) {
-                keepalive_clone.write_all(&[codes::KEEP_ALIVE]).unwrap();
+    /// Ask to change our nickname. [`Client::nick`] changes once the server confirms it
+    pub fn change_nick(&self, nick: &str) -> Result<()> {
+        check_name("nickname", nick)?;
+        self.send(&Message::ChangeNick {
+            old: self.nick(),
+            nick: nick.to_string(),
+        })
+    }
+
+    /// Tell the server we are leaving and close the connection
+    pub fn quit(&self) -> Result<()> {
+        let mut writer: MutexGuard<'_, Stream> = lock(&self.shared.writer);
+        let sent: io::Result<()> = send(&mut *writer, &Message::Quit);
+        // Nothing more to do if the server is already gone
+        let _ = writer.shutdown(Shutdown::Both);
+        Ok(sent?)
+    }
+
+    /// Every message from the server in the order it arrived, waiting for each.
+    /// An error which ends the connection, including [`Error::Closed`], is the last item.
+    /// A message which could not be decoded is an [`Error::Decode`] item, and later messages still follow
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { client: self }
+    }
+
+    /// Call `handler` with every message from the server until the connection closes or `handler` fails.
+    /// Returns Ok once the server closes the connection, or the error that ended it.
+    /// Messages which could not be decoded are skipped
+    pub fn run<F>(&self, mut handler: F) -> Result<()>
+    where
+        F: FnMut(&Client, Message) -> Result<()>,
+    {
+        for message in self.incoming() {
+            match message {
+                Ok(message) => handler(self, message)?,
+                Err(Error::Decode(_)) => {}
+                Err(Error::Closed) => return Ok(()),
+                Err(e) => return Err(e),
             }
-        });
+        }
+        Ok(())
+    }
+}
 
-        //try to register the nickname
-        let nick_reg_buff: Vec<u8> = one_param_buf(codes::REGISTER_NICK, &nick);
-        stream.write_all(&nick_reg_buff).unwrap();
-        loop {
-            let inp: String = input!("");
-
-            match inp.split_once(' ') {
-                Some((cmd, param)) => match cmd {
-                    "/list" => match param.split_once(' ') {
-                        Some((_, _)) => {
-                            eprintln!("Malformaed. Try /list [room-name]");
-                        }
-                        _ => {
-                            let out_buf: Vec<u8> = one_param_buf(codes::LIST_USERS_IN_ROOM, param);
-                            stream.write_all(&out_buf).unwrap();
-                        }
-                    },
-                    "/join" => match param.split_once(' ') {
-                        Some((_, _)) => {
-                            eprintln!("Malformed. Try /join [room-name]");
-                        }
-                        _ => {
-                            let out_buf: Vec<u8> = one_param_buf(codes::JOIN_ROOM, param);
-                            stream.write_all(&out_buf).unwrap();
-                        }
-                    },
-
-                    "/leave" => match param.split_once(' ') {
-                        Some((_, _)) => {
-                            eprintln!("Malformed. Try /leave [room-name]");
-                        }
-                        _ => {
-                            let out_buf: Vec<u8> = one_param_buf(codes::LEAVE_ROOM, param);
-                            stream.write_all(&out_buf).unwrap();
-                        }
-                    },
-                    "/msg" => match param.split_once(' ') {
-                        Some((room, msg)) => {
-                            let out_buf: Vec<u8> = two_param_buf(codes::MESSAGE_ROOM, room, msg);
-                            stream.write_all(&out_buf).unwrap();
-                        }
-                        _ => {
-                            eprintln!("Usage: /msg [room] [message]");
-                        }
-                    },
-                    _ => {
-                        let out_buf: Vec<u8> = one_param_buf(codes::MESSAGE, &inp);
-                        stream.write_all(&out_buf).unwrap();
-                    }
-                },
-
-                _ => match inp.as_str() {
-                    "/quit" => {
-                        disconnect(&mut stream);
-                        break;
-                    }
-                    "/rooms" => {
-                        let out_buf: [u8; 1] = one_op_buf(codes::LIST_ROOMS);
-                        stream.write_all(&out_buf).unwrap();
-                    }
-                    "/users" => {
-                        let out_buf: [u8; 1] = one_op_buf(codes::LIST_USERS);
-                        stream.write_all(&out_buf).unwrap();
-                    }
-                    "/help" => {
-                        help();
-                    }
-                    "/" => {
-                        eprintln!("Invalid command");
-                    }
-                    _ => {
-                        let out_buf: Vec<u8> = one_param_buf(codes::MESSAGE, &inp);
-                        stream.write_all(&out_buf).unwrap();
-                    }
-                },
+impl Drop for Client {
+    /// Close the connection, which also stops the thread reading it
+    fn drop(&mut self) {
+        let _ = lock(&self.shared.writer).shutdown(Shutdown::Both);
+    }
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        lock(&self.client.incoming).recv().ok()
+    }
+}
+
+/// Open a TCP connection to the first address of `host` which accepts one within `timeout`
+fn open(host: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut failed: io::Error = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} has no addresses", host),
+    );
+    for addr in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => failed = e,
+        }
+    }
+    Err(failed)
+}
+
+fn unexpected(message: &Message) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "unexpected opcode {:#04x} while registering",
+            message.opcode()
+        ),
+    ))
+}
+
+/// Read messages until the connection ends, passing them on through `incoming`.
+/// Keep alive probes are answered here, so that a slow reader of `incoming` is not timed out
+fn read_messages(
+    mut reader: FrameReader<Stream>,
+    shared: &Shared,
+    incoming: Sender<Result<Message>>,
+) {
+    loop {
+        let result: Result<Message> = read_message(&mut reader);
+        if let Ok(_) | Err(Error::Decode(_)) = result {
+            *lock(&shared.last_heard) = Instant::now();
+        }
+        match result {
+            Ok(Message::KeepAlive) => {
+                let _ = send(&mut *lock(&shared.writer), &Message::ResponseOk);
+                continue;
+            }
+            Ok(Message::ChangeNick { ref old, ref nick }) => {
+                let mut current: MutexGuard<'_, String> = lock(&shared.nick);
+                if *current == *old {
+                    *current = nick.to_string();
+                }
             }
+            _ => {}
+        }
+        let ended: bool = matches!(result, Err(Error::Closed) | Err(Error::Io(_)));
+        if incoming.send(result).is_err() || ended {
+            return;
         }
-    } else {
-        println!("Failed to connect to {} with nickname {} ", host, nick);
     }
 }
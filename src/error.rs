@@ -0,0 +1,57 @@
+//! The error type shared by everything which talks to a peer over the protocol.
+
+use std::fmt;
+use std::io;
+
+use crate::protocol::{DecodeError, ErrorCode};
+
+/// Why reading or writing a message failed
+#[derive(Debug)]
+pub enum Error {
+    /// The peer closed the connection cleanly
+    Closed,
+    /// The connection failed while reading or writing
+    Io(io::Error),
+    /// A whole frame arrived, but it is not a valid message
+    Decode(DecodeError),
+    /// The server answered a request with an error, such as refusing a nickname
+    Refused(ErrorCode),
+    /// A request the server would reject, such as a room name with a space in it, so it was never sent
+    Invalid(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Closed => write!(f, "connection closed"),
+            Error::Io(e) => write!(f, "connection error: {}", e),
+            Error::Decode(e) => write!(f, "invalid message: {}", e),
+            Error::Refused(code) => write!(f, "refused by the server: {}", code),
+            Error::Invalid(reason) => write!(f, "invalid request: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Closed | Error::Refused(_) | Error::Invalid(_) => None,
+            Error::Io(e) => Some(e),
+            Error::Decode(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
@@ -0,0 +1,160 @@
+//! Message framing for the wire protocol.
+//!
+//! Every message (an opcode followed by its parameters) travels inside a frame: a 4 byte
+//! big-endian length prefix followed by exactly that many payload bytes. TCP is a byte stream,
+//! so without this two messages written back to back may arrive in a single read, and a large
+//! message may arrive across several reads.
+//!
+//! [`FrameReader`] and [`write_frame`] work on blocking streams, and [`AsyncFrameReader`] and
+//! [`write_frame_async`] on asynchronous ones, such as the server's connections.
+
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Number of bytes in the length prefix of each frame
+pub const HEADER_LEN: usize = 4;
+
+/// Largest payload a peer may send in a single frame
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Wrap a payload in a frame, ready to be written to a stream
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let len_buf: [u8; HEADER_LEN] = (payload.len() as u32).to_be_bytes();
+    [&len_buf, payload].concat()
+}
+
+/// Write one framed payload to the given writer
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    check_len(payload)?;
+    writer.write_all(&frame(payload))
+}
+
+/// Write one framed payload to the given asynchronous writer
+pub async fn write_frame_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+) -> io::Result<()> {
+    check_len(payload)?;
+    writer.write_all(&frame(payload)).await
+}
+
+fn check_len(payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "payload exceeds the maximum frame length",
+        ));
+    }
+    Ok(())
+}
+
+/// Reads whole frames from an underlying reader, buffering partial frames between reads
+pub struct FrameReader<R: Read> {
+    inner: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        FrameReader {
+            inner,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Block until a whole frame is available and return its payload.
+    /// Returns `Ok(None)` when the peer closes the connection between frames,
+    /// and an `UnexpectedEof` error if it closes part way through one.
+    pub fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk: [u8; 1024] = [0; 1024];
+        loop {
+            if let Some(payload) = next_buffered(&mut self.buffer)? {
+                return Ok(Some(payload));
+            }
+            match self.inner.read(&mut chunk) {
+                Ok(0) => return end_of_stream(&self.buffer),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reads whole frames from an asynchronous reader, like [`FrameReader`] does from a blocking one
+pub struct AsyncFrameReader<R: AsyncRead + Unpin> {
+    inner: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncFrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        AsyncFrameReader {
+            inner,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Wait until a whole frame is available and return its payload, as [`FrameReader::read_frame`] does.
+    /// Cancelling the wait loses nothing, since bytes of a partial frame stay buffered for the next call
+    pub async fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk: [u8; 1024] = [0; 1024];
+        loop {
+            if let Some(payload) = next_buffered(&mut self.buffer)? {
+                return Ok(Some(payload));
+            }
+            match self.inner.read(&mut chunk).await {
+                Ok(0) => return end_of_stream(&self.buffer),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Pop a complete frame off the front of the buffer, if one has fully arrived
+fn next_buffered(buffer: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buffer.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let mut len_buf: [u8; HEADER_LEN] = [0; HEADER_LEN];
+    len_buf.copy_from_slice(&buffer[..HEADER_LEN]);
+    let len: usize = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame exceeds the maximum frame length",
+        ));
+    }
+    if buffer.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+    let payload: Vec<u8> = buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+    buffer.drain(..HEADER_LEN + len);
+    Ok(Some(payload))
+}
+
+/// The result of the peer closing the connection, which is only clean between frames
+fn end_of_stream(buffer: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed part way through a frame",
+    ))
+}
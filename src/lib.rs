@@ -1,3 +1,15 @@
+mod accounts;
+pub mod bot;
+pub mod client;
+pub mod error;
+pub mod framing;
+pub mod protocol;
+pub mod server;
+pub mod tls;
+pub mod transport;
+
+pub use error::{Error, Result};
+
 pub mod codes {
     pub const TBD: u8 = 0x00;
     pub const JOIN_ROOM: u8 = 0x01;
@@ -9,26 +21,86 @@ pub mod codes {
     pub const LIST_USERS: u8 = 0x07;
     pub const LIST_USERS_IN_ROOM: u8 = 0x08;
     pub const MESSAGE_ROOM: u8 = 0x09;
+    pub const SERVER_INFO: u8 = 0x0A;
     pub const QUIT: u8 = 0x0B;
     pub const KEEP_ALIVE: u8 = 0x0C;
     pub const RESPONSE: u8 = 0x0D;
     pub const RESPONSE_OK: u8 = 0x0E;
     pub const ERROR: u8 = 0x0F;
+    pub const PRIVATE_MESSAGE: u8 = 0x20;
+    pub const KICK: u8 = 0x21;
+    pub const BAN: u8 = 0x22;
+    pub const UNBAN: u8 = 0x23;
+    pub const OP: u8 = 0x24;
+    pub const DEOP: u8 = 0x25;
+    pub const SET_TOPIC: u8 = 0x26;
+    pub const GET_TOPIC: u8 = 0x27;
+    pub const CHANGE_NICK: u8 = 0x28;
+    pub const MEMBERSHIP: u8 = 0x29;
+    pub const GET_HISTORY: u8 = 0x2A;
+    pub const HISTORY: u8 = 0x2B;
+    pub const PERSIST: u8 = 0x2C;
+    pub const REGISTER_ACCOUNT: u8 = 0x2D;
 
     pub mod error {
         pub const INVALID_ROOM: u8 = 0x10;
         pub const NICKNAME_COLLISION: u8 = 0x11;
-        pub const SERVER_FULL: u8 = 0x12; // Not used
+        pub const SERVER_FULL: u8 = 0x12;
         pub const ALREADY_REGISTERED: u8 = 0x13;
         pub const NOT_YET_REGISTERED: u8 = 0x14;
         pub const MALFORMED: u8 = 0x15;
         pub const ALREADY_IN_ROOM: u8 = 0x16;
         pub const NOT_IN_ROOM: u8 = 0x17;
         pub const EMPTY_ROOM: u8 = 0x18;
+        pub const VERSION_MISMATCH: u8 = 0x19;
+        pub const NO_SUCH_USER: u8 = 0x1A;
+        pub const NOT_OPERATOR: u8 = 0x1B;
+        pub const BANNED: u8 = 0x1C;
+        pub const BAD_CREDENTIALS: u8 = 0x1D;
     }
 }
 
+/// Names of optional protocol features, exchanged in the JOIN_SERVER handshake
+pub mod capabilities {
+    pub const PRIVATE_MESSAGES: &str = "private-messages";
+    pub const OPERATORS: &str = "operators";
+    pub const TOPICS: &str = "topics";
+    pub const NICK_CHANGES: &str = "nick-changes";
+    pub const MEMBERSHIP_EVENTS: &str = "membership-events";
+    pub const HISTORY: &str = "history";
+    pub const PERSISTENT_ROOMS: &str = "persistent-rooms";
+    pub const ACCOUNTS: &str = "accounts";
+}
+
+use std::net::{IpAddr, SocketAddr};
+
 pub const DEFAULT_PORT: u16 = 6667;
+/// Protocol version spoken by this build, announced in the JOIN_SERVER handshake
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this build will still accept from a peer
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Combine a host with a port, unless the host already includes one.
+/// IPv6 addresses must be in brackets when they include a port, such as `[::1]:6667`
+pub fn host_with_port(host: &str, port: u16) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        return host.to_string();
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) if host.contains(':') => host.to_string(),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+/// Read one line from stdin, without its line ending. None when stdin is closed
+pub fn read_line() -> Option<String> {
+    let mut line: String = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+    }
+}
+
 pub fn clear() {
     print!("\x1B[2J");
 }
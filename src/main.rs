@@ -1,29 +1,242 @@
 use std::env;
+use std::time::Duration;
 
-mod client;
-mod server;
+use rust_irc::server::OverflowPolicy;
+use rust_irc::tls::{parse_fingerprint, Verify};
+use rust_irc::{host_with_port, DEFAULT_PORT};
 
-fn parsechar(s: &str) -> char {
-    s.parse().unwrap_or_else(|_| info())
-}
+mod admin;
+mod terminal;
+
+/// Comma separated addresses for the server to listen on, overridden by `--bind`
+const BIND_ENV: &str = "RUST_IRC_BIND";
+/// Port for the server to listen on, overridden by `--port`
+const PORT_ENV: &str = "RUST_IRC_PORT";
+/// Run the server without the admin menu when set to 1 or true, same as `--headless`
+const HEADLESS_ENV: &str = "RUST_IRC_HEADLESS";
+/// Password of a registered nickname, kept out of the command line where other users could see it
+const PASSWORD_ENV: &str = "RUST_IRC_PASSWORD";
 
 fn info() -> ! {
-    println!("Start client: cargro run c\nStart server: cargo run s");
+    println!("Start client: cargo run c [options]\nStart server: cargo run s [options]");
+    println!("Client options (prompted for when not given):");
+    println!("  --nick [nickname] <- Nickname to register");
+    println!("  --host [host] <- Server host, with or without a port");
+    println!("  --port [port] <- Server port, default {}", DEFAULT_PORT);
+    println!(
+        "  --ask-password <- Prompt for the password of a registered nickname (or set env {})",
+        PASSWORD_ENV
+    );
+    println!("  --tls <- Connect over TLS, trusting the usual public certificate authorities");
+    println!(
+        "  --tls-ca [path] <- Connect over TLS, trusting the certificate authorities in a PEM file"
+    );
+    println!("  --tls-fingerprint [hex] <- Connect over TLS, trusting only the certificate with this SHA-256 fingerprint");
+    println!("  --join [room-name] <- Room to join after registering. May be given more than once");
+    println!("Server options:");
+    println!(
+        "  --bind [address] <- Address to listen on, with or without a port. May be given more than once (env {})",
+        BIND_ENV
+    );
+    println!(
+        "  --port [port] <- Port for bind addresses given without one, default {} (env {})",
+        DEFAULT_PORT, PORT_ENV
+    );
+    println!(
+        "  --headless <- Run without the admin menu until SIGTERM/SIGINT, alias --daemon (env {})",
+        HEADLESS_ENV
+    );
+    println!(
+        "  --queue-len [count] <- Messages that may wait to be sent to one client, default 256"
+    );
+    println!("  --overflow [drop|disconnect] <- What to do when a client's queue is full, default disconnect");
+    println!("  --max-users [count] <- Most users registered at once, default no limit");
+    println!("  --max-pending [count] <- Most connections not yet registered, default no limit");
+    println!("  --max-per-ip [count] <- Most connections from one IP address, default no limit");
+    println!("  --history [count] <- Recent messages each room keeps, default 100, 0 for none");
+    println!("  --history-on-join [count] <- Recent messages sent on joining a room, default 10");
+    println!("  --data-dir [path] <- Directory persistent rooms are saved in and loaded from");
+    println!("  --persist-history <- Also save the recent messages of persistent rooms");
+    println!("  --tls-cert [path] <- Serve TLS with the certificate chain in this PEM file. Needs --tls-key");
+    println!("  --tls-key [path] <- Private key of the TLS certificate, as a PEM file");
+    println!(
+        "  --keepalive [seconds] <- Probe clients silent this long with KEEP_ALIVE, default 10"
+    );
+    println!("  --idle-timeout [seconds] <- Disconnect clients silent this long, default 30");
     std::process::exit(1)
 }
 
+/// The value following a command line flag
+fn flag_value(flag: &str, value: Option<&String>) -> Result<String, String> {
+    value
+        .cloned()
+        .ok_or_else(|| format!("Missing value for {}", flag))
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("Invalid count {}", value)),
+    }
+}
+
+/// A count which may be 0, to turn a feature off
+fn parse_count_or_zero(value: &str) -> Result<usize, String> {
+    value
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid count {}", value))
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("Invalid number of seconds {}", value)),
+    }
+}
+
+fn parse_port(value: &str) -> Result<u16, String> {
+    value
+        .parse::<u16>()
+        .map_err(|_| format!("Invalid port {}", value))
+}
+
+/// Build the client options from command line flags
+fn client_options(args: &[String]) -> Result<terminal::Options, String> {
+    let mut options: terminal::Options = terminal::Options {
+        password: env::var(PASSWORD_ENV).ok(),
+        ..terminal::Options::default()
+    };
+    let mut args: std::slice::Iter<'_, String> = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--nick" => {
+                let nick: String = flag_value(arg, args.next())?;
+                if nick.is_empty() || nick.contains(' ') {
+                    return Err(format!("Invalid nickname {}", nick));
+                }
+                options.nick = Some(nick);
+            }
+            "--host" => options.host = Some(flag_value(arg, args.next())?),
+            "--port" => options.port = Some(parse_port(&flag_value(arg, args.next())?)?),
+            "--ask-password" => options.ask_password = true,
+            "--tls" => {
+                if options.tls.is_none() {
+                    options.tls = Some(Verify::PublicRoots);
+                }
+            }
+            "--tls-ca" => options.tls = Some(Verify::CaFile(flag_value(arg, args.next())?.into())),
+            "--tls-fingerprint" => {
+                let value: String = flag_value(arg, args.next())?;
+                match parse_fingerprint(&value) {
+                    Some(fingerprint) => options.tls = Some(Verify::Fingerprint(fingerprint)),
+                    None => return Err(format!("Invalid SHA-256 fingerprint {}", value)),
+                }
+            }
+            "--join" => {
+                let room: String = flag_value(arg, args.next())?;
+                if room.is_empty() || room.contains(' ') {
+                    return Err(format!("Invalid room name {}", room));
+                }
+                options.join.push(room);
+            }
+            other => return Err(format!("Unknown client option {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+/// Build the server options from environment variables, then command line flags
+fn server_options(args: &[String]) -> Result<admin::Options, String> {
+    let mut options: admin::Options = admin::Options::default();
+    let config: &mut rust_irc::server::Config = &mut options.config;
+    let mut binds: Vec<String> = match env::var(BIND_ENV) {
+        Ok(value) => value
+            .split(',')
+            .map(|b: &str| b.trim().to_string())
+            .filter(|b: &String| !b.is_empty())
+            .collect(),
+        Err(_) => vec![],
+    };
+    let mut port: u16 = match env::var(PORT_ENV) {
+        Ok(value) => parse_port(&value)?,
+        Err(_) => DEFAULT_PORT,
+    };
+    options.headless = matches!(env::var(HEADLESS_ENV).as_deref(), Ok("1") | Ok("true"));
+
+    let mut flag_binds: Vec<String> = vec![];
+    let mut args: std::slice::Iter<'_, String> = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => flag_binds.push(flag_value(arg, args.next())?),
+            "--port" => port = parse_port(&flag_value(arg, args.next())?)?,
+            "--headless" | "--daemon" => options.headless = true,
+            "--queue-len" => config.queue_len = parse_count(&flag_value(arg, args.next())?)?,
+            "--max-users" => config.max_users = Some(parse_count(&flag_value(arg, args.next())?)?),
+            "--max-pending" => {
+                config.max_pending = Some(parse_count(&flag_value(arg, args.next())?)?)
+            }
+            "--max-per-ip" => {
+                config.max_per_ip = Some(parse_count(&flag_value(arg, args.next())?)?)
+            }
+            "--history" => {
+                config.history_len = parse_count_or_zero(&flag_value(arg, args.next())?)?
+            }
+            "--history-on-join" => {
+                config.history_on_join = parse_count_or_zero(&flag_value(arg, args.next())?)?
+            }
+            "--data-dir" => config.data_dir = Some(flag_value(arg, args.next())?.into()),
+            "--persist-history" => config.persist_history = true,
+            "--tls-cert" => config.tls_cert = Some(flag_value(arg, args.next())?.into()),
+            "--tls-key" => config.tls_key = Some(flag_value(arg, args.next())?.into()),
+            "--keepalive" => {
+                config.keepalive_interval = parse_seconds(&flag_value(arg, args.next())?)?
+            }
+            "--idle-timeout" => {
+                config.idle_timeout = parse_seconds(&flag_value(arg, args.next())?)?
+            }
+            "--overflow" => {
+                config.overflow = match flag_value(arg, args.next())?.as_str() {
+                    "drop" => OverflowPolicy::Drop,
+                    "disconnect" => OverflowPolicy::Disconnect,
+                    other => return Err(format!("Invalid overflow policy {}", other)),
+                };
+            }
+            other => return Err(format!("Unknown server option {}", other)),
+        }
+    }
+    if !flag_binds.is_empty() {
+        binds = flag_binds;
+    }
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        return Err("--tls-cert and --tls-key must be given together".to_string());
+    }
+    if binds.is_empty() {
+        binds.push("0.0.0.0".to_string());
+    }
+    config.listen = binds
+        .iter()
+        .map(|b: &String| host_with_port(b, port))
+        .collect();
+    Ok(options)
+}
+
 fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
-    if args.len() == 1 {
-        let input: char = parsechar(&args[0]);
-        if input == 'c' {
-            client::start();
-        } else if input == 's' {
-            server::start();
-        } else {
-            info();
-        }
-    } else {
-        info();
+    match args.first().map(String::as_str) {
+        Some("c") => match client_options(&args[1..]) {
+            Ok(options) => terminal::start(options),
+            Err(e) => {
+                eprintln!("{}", e);
+                info();
+            }
+        },
+        Some("s") => match server_options(&args[1..]) {
+            Ok(options) => admin::start(options),
+            Err(e) => {
+                eprintln!("{}", e);
+                info();
+            }
+        },
+        _ => info(),
     }
 }
@@ -0,0 +1,626 @@
+//! Typed messages for every opcode in [`codes`], shared by the client and the server.
+//!
+//! A message is encoded as its opcode followed by space separated parameters. Only the last
+//! parameter of a message may contain spaces, so it is always taken as the remainder of the payload.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::buf_helpers::{one_op_buf, one_param_buf, three_param_buf, two_op_buf, two_param_buf};
+use crate::codes;
+use crate::framing::{write_frame, write_frame_async, AsyncFrameReader, FrameReader};
+
+/// Error codes which follow the `ERROR` opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    InvalidRoom,
+    NicknameCollision,
+    ServerFull,
+    AlreadyRegistered,
+    NotYetRegistered,
+    Malformed,
+    AlreadyInRoom,
+    NotInRoom,
+    EmptyRoom,
+    VersionMismatch,
+    NoSuchUser,
+    NotOperator,
+    Banned,
+    /// The nickname is registered, and the password given for it was missing or wrong
+    BadCredentials,
+    /// An error code this version of the protocol does not know about
+    Unknown(u8),
+}
+
+impl ErrorCode {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            codes::error::INVALID_ROOM => ErrorCode::InvalidRoom,
+            codes::error::NICKNAME_COLLISION => ErrorCode::NicknameCollision,
+            codes::error::SERVER_FULL => ErrorCode::ServerFull,
+            codes::error::ALREADY_REGISTERED => ErrorCode::AlreadyRegistered,
+            codes::error::NOT_YET_REGISTERED => ErrorCode::NotYetRegistered,
+            codes::error::MALFORMED => ErrorCode::Malformed,
+            codes::error::ALREADY_IN_ROOM => ErrorCode::AlreadyInRoom,
+            codes::error::NOT_IN_ROOM => ErrorCode::NotInRoom,
+            codes::error::EMPTY_ROOM => ErrorCode::EmptyRoom,
+            codes::error::VERSION_MISMATCH => ErrorCode::VersionMismatch,
+            codes::error::NO_SUCH_USER => ErrorCode::NoSuchUser,
+            codes::error::NOT_OPERATOR => ErrorCode::NotOperator,
+            codes::error::BANNED => ErrorCode::Banned,
+            codes::error::BAD_CREDENTIALS => ErrorCode::BadCredentials,
+            other => ErrorCode::Unknown(other),
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::InvalidRoom => codes::error::INVALID_ROOM,
+            ErrorCode::NicknameCollision => codes::error::NICKNAME_COLLISION,
+            ErrorCode::ServerFull => codes::error::SERVER_FULL,
+            ErrorCode::AlreadyRegistered => codes::error::ALREADY_REGISTERED,
+            ErrorCode::NotYetRegistered => codes::error::NOT_YET_REGISTERED,
+            ErrorCode::Malformed => codes::error::MALFORMED,
+            ErrorCode::AlreadyInRoom => codes::error::ALREADY_IN_ROOM,
+            ErrorCode::NotInRoom => codes::error::NOT_IN_ROOM,
+            ErrorCode::EmptyRoom => codes::error::EMPTY_ROOM,
+            ErrorCode::VersionMismatch => codes::error::VERSION_MISMATCH,
+            ErrorCode::NoSuchUser => codes::error::NO_SUCH_USER,
+            ErrorCode::NotOperator => codes::error::NOT_OPERATOR,
+            ErrorCode::Banned => codes::error::BANNED,
+            ErrorCode::BadCredentials => codes::error::BAD_CREDENTIALS,
+            ErrorCode::Unknown(byte) => byte,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::InvalidRoom => write!(f, "no such room"),
+            ErrorCode::NicknameCollision => write!(f, "nickname already in use"),
+            ErrorCode::ServerFull => write!(f, "server is full"),
+            ErrorCode::AlreadyRegistered => write!(f, "already registered"),
+            ErrorCode::NotYetRegistered => write!(f, "not registered yet"),
+            ErrorCode::Malformed => write!(f, "malformed message"),
+            ErrorCode::AlreadyInRoom => write!(f, "already in the room"),
+            ErrorCode::NotInRoom => write!(f, "not in the room"),
+            ErrorCode::EmptyRoom => write!(f, "room is empty"),
+            ErrorCode::VersionMismatch => write!(f, "unsupported protocol version"),
+            ErrorCode::NoSuchUser => write!(f, "no such user"),
+            ErrorCode::NotOperator => write!(f, "not an operator of the room"),
+            ErrorCode::Banned => write!(f, "banned from the room"),
+            ErrorCode::BadCredentials => write!(f, "wrong or missing password"),
+            ErrorCode::Unknown(byte) => write!(f, "unknown error {:#04x}", byte),
+        }
+    }
+}
+
+/// How a user's membership of a room changed, sent in `MEMBERSHIP`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipChange {
+    Joined,
+    Left,
+    /// Disconnected from the server
+    Quit,
+    /// Disconnected by the server for being silent too long
+    TimedOut,
+}
+
+impl MembershipChange {
+    /// The word for the change on the wire
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MembershipChange::Joined => "joined",
+            MembershipChange::Left => "left",
+            MembershipChange::Quit => "quit",
+            MembershipChange::TimedOut => "timeout",
+        }
+    }
+
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            "joined" => Some(MembershipChange::Joined),
+            "left" => Some(MembershipChange::Left),
+            "quit" => Some(MembershipChange::Quit),
+            "timeout" => Some(MembershipChange::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+/// One message of the protocol, in either direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    JoinRoom {
+        room: String,
+    },
+    /// Sent by a client before registering, to announce its protocol version and capabilities
+    JoinServer {
+        version: u16,
+        capabilities: Vec<String>,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    ListRooms,
+    /// From a client: a message for every room the sender has joined.
+    /// From the server: a message from the server itself
+    Message {
+        text: String,
+    },
+    /// The password is only needed for a nickname registered as an account, and is otherwise ignored
+    RegisterNick {
+        nick: String,
+        password: Option<String>,
+    },
+    ListUsers,
+    ListUsersInRoom {
+        room: String,
+    },
+    /// The server ignores the sender given by a client, and uses its registered nickname instead
+    MessageRoom {
+        room: String,
+        sender: String,
+        text: String,
+    },
+    /// The server's reply to `JoinServer`
+    ServerInfo {
+        version: u16,
+        name: String,
+        capabilities: Vec<String>,
+    },
+    Quit,
+    KeepAlive,
+    Response(String),
+    ResponseOk,
+    Error(ErrorCode),
+    /// From a client: `nick` is the recipient. From the server: `nick` is the sender
+    PrivateMessage {
+        nick: String,
+        text: String,
+    },
+    /// Room moderation, which only the room's operators may use.
+    /// From a client: `nick` is the user acted on.
+    /// From the server: sent to the user acted on, and `nick` is the operator who acted
+    Kick {
+        room: String,
+        nick: String,
+    },
+    Ban {
+        room: String,
+        nick: String,
+    },
+    Unban {
+        room: String,
+        nick: String,
+    },
+    Op {
+        room: String,
+        nick: String,
+    },
+    Deop {
+        room: String,
+        nick: String,
+    },
+    /// From a client: set the topic of a room, or clear it with an empty topic.
+    /// From the server: the topic of a room the user is in has changed
+    SetTopic {
+        room: String,
+        topic: String,
+    },
+    GetTopic {
+        room: String,
+    },
+    /// From a client: change its nickname from `old` to `nick`. As with `MessageRoom`, the server
+    /// ignores the `old` nickname given by a client and uses its registered nickname instead.
+    /// From the server: the user `old`, who shares a room with the recipient or is the recipient, is now `nick`
+    ChangeNick {
+        old: String,
+        nick: String,
+    },
+    /// Only from the server: `nick` joined or left `room`, which the recipient is a member of
+    Membership {
+        room: String,
+        nick: String,
+        change: MembershipChange,
+    },
+    /// Ask for up to `count` of the most recent messages sent to a room the user is in
+    GetHistory {
+        room: String,
+        count: u32,
+    },
+    /// Only from the server: one message sent to `room` earlier, at `time` seconds since the Unix epoch.
+    /// Sent oldest first, in reply to `GetHistory` and after joining a room
+    History {
+        room: String,
+        time: u64,
+        sender: String,
+        text: String,
+    },
+    /// From a client: mark a room persistent, so it outlives its last member and server restarts, or not.
+    /// From the server: whether a room the user is in is persistent has changed
+    Persist {
+        room: String,
+        persistent: bool,
+    },
+    /// Register the sender's nickname as an account with a password, or change the password of its account
+    RegisterAccount {
+        password: String,
+    },
+}
+
+/// Why a payload could not be decoded into a [`Message`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The payload had no opcode at all
+    Empty,
+    /// The opcode is not one this version of the protocol knows about
+    UnknownOpcode(u8),
+    /// The parameters are missing or in the wrong format for the opcode
+    Malformed(u8),
+    /// The parameters of the opcode are not valid UTF-8
+    InvalidUtf8(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty message"),
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown opcode {:#04x}", op),
+            DecodeError::Malformed(op) => write!(f, "malformed parameters for opcode {:#04x}", op),
+            DecodeError::InvalidUtf8(op) => {
+                write!(f, "parameters for opcode {:#04x} are not UTF-8", op)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Message {
+    /// The opcode this message is sent with
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Message::JoinRoom { .. } => codes::JOIN_ROOM,
+            Message::JoinServer { .. } => codes::JOIN_SERVER,
+            Message::LeaveRoom { .. } => codes::LEAVE_ROOM,
+            Message::ListRooms => codes::LIST_ROOMS,
+            Message::Message { .. } => codes::MESSAGE,
+            Message::RegisterNick { .. } => codes::REGISTER_NICK,
+            Message::ListUsers => codes::LIST_USERS,
+            Message::ListUsersInRoom { .. } => codes::LIST_USERS_IN_ROOM,
+            Message::MessageRoom { .. } => codes::MESSAGE_ROOM,
+            Message::ServerInfo { .. } => codes::SERVER_INFO,
+            Message::Quit => codes::QUIT,
+            Message::KeepAlive => codes::KEEP_ALIVE,
+            Message::Response(_) => codes::RESPONSE,
+            Message::ResponseOk => codes::RESPONSE_OK,
+            Message::Error(_) => codes::ERROR,
+            Message::PrivateMessage { .. } => codes::PRIVATE_MESSAGE,
+            Message::Kick { .. } => codes::KICK,
+            Message::Ban { .. } => codes::BAN,
+            Message::Unban { .. } => codes::UNBAN,
+            Message::Op { .. } => codes::OP,
+            Message::Deop { .. } => codes::DEOP,
+            Message::SetTopic { .. } => codes::SET_TOPIC,
+            Message::GetTopic { .. } => codes::GET_TOPIC,
+            Message::ChangeNick { .. } => codes::CHANGE_NICK,
+            Message::Membership { .. } => codes::MEMBERSHIP,
+            Message::GetHistory { .. } => codes::GET_HISTORY,
+            Message::History { .. } => codes::HISTORY,
+            Message::Persist { .. } => codes::PERSIST,
+            Message::RegisterAccount { .. } => codes::REGISTER_ACCOUNT,
+        }
+    }
+
+    /// Encode the message as an opcode followed by its parameters, without framing
+    pub fn encode(&self) -> Vec<u8> {
+        let op: u8 = self.opcode();
+        match self {
+            Message::JoinRoom { room }
+            | Message::LeaveRoom { room }
+            | Message::ListUsersInRoom { room }
+            | Message::GetTopic { room } => one_param_buf(op, room),
+            Message::Message { text } | Message::Response(text) => one_param_buf(op, text),
+            Message::RegisterNick {
+                nick,
+                password: None,
+            } => one_param_buf(op, nick),
+            Message::RegisterNick {
+                nick,
+                password: Some(password),
+            } => two_param_buf(op, nick, password),
+            Message::RegisterAccount { password } => one_param_buf(op, password),
+            Message::MessageRoom { room, sender, text } => three_param_buf(op, room, sender, text),
+            Message::JoinServer {
+                version,
+                capabilities,
+            } => {
+                let mut params: Vec<String> = vec![version.to_string()];
+                params.extend(capabilities.iter().cloned());
+                one_param_buf(op, &params.join(" "))
+            }
+            Message::ServerInfo {
+                version,
+                name,
+                capabilities,
+            } => {
+                let mut params: Vec<String> = vec![version.to_string(), name.to_string()];
+                params.extend(capabilities.iter().cloned());
+                one_param_buf(op, &params.join(" "))
+            }
+            Message::PrivateMessage { nick, text } => two_param_buf(op, nick, text),
+            Message::Kick { room, nick }
+            | Message::Ban { room, nick }
+            | Message::Unban { room, nick }
+            | Message::Op { room, nick }
+            | Message::Deop { room, nick } => two_param_buf(op, room, nick),
+            Message::SetTopic { room, topic } => two_param_buf(op, room, topic),
+            Message::ChangeNick { old, nick } => two_param_buf(op, old, nick),
+            Message::Membership { room, nick, change } => {
+                three_param_buf(op, room, nick, change.as_str())
+            }
+            Message::GetHistory { room, count } => two_param_buf(op, room, &count.to_string()),
+            Message::History {
+                room,
+                time,
+                sender,
+                text,
+            } => {
+                let time_and_sender: String = format!("{} {}", time, sender);
+                three_param_buf(op, room, &time_and_sender, text)
+            }
+            Message::Persist { room, persistent } => {
+                two_param_buf(op, room, if *persistent { "on" } else { "off" })
+            }
+            Message::Error(code) => two_op_buf(op, code.to_byte()).to_vec(),
+            Message::ListRooms
+            | Message::ListUsers
+            | Message::Quit
+            | Message::KeepAlive
+            | Message::ResponseOk => one_op_buf(op).to_vec(),
+        }
+    }
+
+    /// Decode a payload produced by [`Message::encode`]
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (op, param_bytes) = match bytes.split_first() {
+            Some((op, rest)) => (*op, rest),
+            None => return Err(DecodeError::Empty),
+        };
+        if op == codes::ERROR {
+            return match param_bytes.first() {
+                Some(code) => Ok(Message::Error(ErrorCode::from_byte(*code))),
+                None => Err(DecodeError::Malformed(op)),
+            };
+        }
+        let params: &str =
+            std::str::from_utf8(param_bytes).map_err(|_| DecodeError::InvalidUtf8(op))?;
+
+        let message: Message = match op {
+            codes::JOIN_ROOM => Message::JoinRoom {
+                room: one_word(op, params)?,
+            },
+            codes::JOIN_SERVER => {
+                let mut words: std::str::Split<'_, char> = params.split(' ');
+                let version: u16 = parse_version(op, words.next())?;
+                Message::JoinServer {
+                    version,
+                    capabilities: words
+                        .filter(|w: &&str| !w.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                }
+            }
+            codes::LEAVE_ROOM => Message::LeaveRoom {
+                room: one_word(op, params)?,
+            },
+            codes::LIST_ROOMS => Message::ListRooms,
+            codes::MESSAGE => Message::Message {
+                text: params.to_string(),
+            },
+            codes::REGISTER_NICK => match params.split_once(' ') {
+                Some((_, "")) => return Err(DecodeError::Malformed(op)),
+                Some((nick, password)) => Message::RegisterNick {
+                    nick: one_word(op, nick)?,
+                    password: Some(password.to_string()),
+                },
+                None => Message::RegisterNick {
+                    nick: one_word(op, params)?,
+                    password: None,
+                },
+            },
+            codes::LIST_USERS => Message::ListUsers,
+            codes::LIST_USERS_IN_ROOM => Message::ListUsersInRoom {
+                room: one_word(op, params)?,
+            },
+            codes::MESSAGE_ROOM => {
+                let (room, remainder) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+                let (sender, text) = remainder
+                    .split_once(' ')
+                    .ok_or(DecodeError::Malformed(op))?;
+                if room.is_empty() || sender.is_empty() {
+                    return Err(DecodeError::Malformed(op));
+                }
+                Message::MessageRoom {
+                    room: room.to_string(),
+                    sender: sender.to_string(),
+                    text: text.to_string(),
+                }
+            }
+            codes::SERVER_INFO => {
+                let mut words: std::str::Split<'_, char> = params.split(' ');
+                let version: u16 = parse_version(op, words.next())?;
+                let name: String = match words.next() {
+                    Some(name) if !name.is_empty() => name.to_string(),
+                    _ => return Err(DecodeError::Malformed(op)),
+                };
+                Message::ServerInfo {
+                    version,
+                    name,
+                    capabilities: words
+                        .filter(|w: &&str| !w.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                }
+            }
+            codes::QUIT => Message::Quit,
+            codes::KEEP_ALIVE => Message::KeepAlive,
+            codes::RESPONSE => Message::Response(params.to_string()),
+            codes::RESPONSE_OK => Message::ResponseOk,
+            codes::PRIVATE_MESSAGE => {
+                let (nick, text) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+                Message::PrivateMessage {
+                    nick: one_word(op, nick)?,
+                    text: text.to_string(),
+                }
+            }
+            codes::KICK => {
+                let (room, nick) = room_and_nick(op, params)?;
+                Message::Kick { room, nick }
+            }
+            codes::BAN => {
+                let (room, nick) = room_and_nick(op, params)?;
+                Message::Ban { room, nick }
+            }
+            codes::UNBAN => {
+                let (room, nick) = room_and_nick(op, params)?;
+                Message::Unban { room, nick }
+            }
+            codes::OP => {
+                let (room, nick) = room_and_nick(op, params)?;
+                Message::Op { room, nick }
+            }
+            codes::DEOP => {
+                let (room, nick) = room_and_nick(op, params)?;
+                Message::Deop { room, nick }
+            }
+            codes::SET_TOPIC => {
+                let (room, topic) = params.split_once(' ').unwrap_or((params, ""));
+                Message::SetTopic {
+                    room: one_word(op, room)?,
+                    topic: topic.to_string(),
+                }
+            }
+            codes::GET_TOPIC => Message::GetTopic {
+                room: one_word(op, params)?,
+            },
+            codes::CHANGE_NICK => {
+                let (old, nick) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+                Message::ChangeNick {
+                    old: one_word(op, old)?,
+                    nick: one_word(op, nick)?,
+                }
+            }
+            codes::MEMBERSHIP => {
+                let (room, remainder) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+                let (nick, change) = remainder
+                    .split_once(' ')
+                    .ok_or(DecodeError::Malformed(op))?;
+                Message::Membership {
+                    room: one_word(op, room)?,
+                    nick: one_word(op, nick)?,
+                    change: MembershipChange::from_word(change)
+                        .ok_or(DecodeError::Malformed(op))?,
+                }
+            }
+            codes::GET_HISTORY => {
+                let (room, count) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+                Message::GetHistory {
+                    room: one_word(op, room)?,
+                    count: count
+                        .parse::<u32>()
+                        .map_err(|_| DecodeError::Malformed(op))?,
+                }
+            }
+            codes::HISTORY => {
+                let mut words: std::str::SplitN<'_, char> = params.splitn(4, ' ');
+                let room: String = one_word(op, words.next().unwrap_or(""))?;
+                let time: u64 = words
+                    .next()
+                    .and_then(|w: &str| w.parse::<u64>().ok())
+                    .ok_or(DecodeError::Malformed(op))?;
+                let sender: String = one_word(op, words.next().unwrap_or(""))?;
+                let text: &str = words.next().ok_or(DecodeError::Malformed(op))?;
+                Message::History {
+                    room,
+                    time,
+                    sender,
+                    text: text.to_string(),
+                }
+            }
+            codes::PERSIST => {
+                let (room, setting) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+                Message::Persist {
+                    room: one_word(op, room)?,
+                    persistent: match setting {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(DecodeError::Malformed(op)),
+                    },
+                }
+            }
+            codes::REGISTER_ACCOUNT if params.is_empty() => return Err(DecodeError::Malformed(op)),
+            codes::REGISTER_ACCOUNT => Message::RegisterAccount {
+                password: params.to_string(),
+            },
+            other => return Err(DecodeError::UnknownOpcode(other)),
+        };
+        Ok(message)
+    }
+}
+
+/// A parameter which must be a single, non-empty word, such as a nickname or room name
+fn one_word(op: u8, params: &str) -> Result<String, DecodeError> {
+    if params.is_empty() || params.contains(' ') {
+        Err(DecodeError::Malformed(op))
+    } else {
+        Ok(params.to_string())
+    }
+}
+
+/// Parameters which must be exactly a room name and a nickname
+fn room_and_nick(op: u8, params: &str) -> Result<(String, String), DecodeError> {
+    let (room, nick) = params.split_once(' ').ok_or(DecodeError::Malformed(op))?;
+    Ok((one_word(op, room)?, one_word(op, nick)?))
+}
+
+fn parse_version(op: u8, word: Option<&str>) -> Result<u16, DecodeError> {
+    word.and_then(|w: &str| w.parse::<u16>().ok())
+        .ok_or(DecodeError::Malformed(op))
+}
+
+/// Encode, frame and write one message
+pub fn send<W: Write>(writer: &mut W, message: &Message) -> io::Result<()> {
+    write_frame(writer, &message.encode())
+}
+
+/// Read and decode the next message.
+/// A frame which does not decode is consumed, so reading may carry on with the next message
+pub fn read_message<R: Read>(reader: &mut FrameReader<R>) -> crate::Result<Message> {
+    match reader.read_frame()? {
+        Some(bytes) => Ok(Message::decode(&bytes)?),
+        None => Err(crate::Error::Closed),
+    }
+}
+
+/// Encode and write one message to an asynchronous writer
+pub async fn send_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> io::Result<()> {
+    write_frame_async(writer, &message.encode()).await
+}
+
+/// Read and decode the next message from an asynchronous reader, as [`read_message`] does
+pub async fn read_message_async<R: AsyncRead + Unpin>(
+    reader: &mut AsyncFrameReader<R>,
+) -> crate::Result<Message> {
+    match reader.read_frame().await? {
+        Some(bytes) => Ok(Message::decode(&bytes)?),
+        None => Err(crate::Error::Closed),
+    }
+}
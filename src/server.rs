@@ -1,514 +1,2683 @@
-use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+//! The chat server, which can be embedded in another program such as a test rig.
+//!
+//! [`Server`] is built up from the same settings as the command line, plus hooks called with every [`Event`].
+//! [`Server::spawn`] starts serving in the background and returns a [`Handle`], which can look at the users
+//! and rooms and shut the server down.
+//!
+//! ```no_run
+//! use rust_irc::server::{Event, Handle, Server};
+//!
+//! let handle: Handle = Server::new()
+//!     .bind("127.0.0.1:0")
+//!     .max_users(100)
+//!     .on_event(|event: &Event| println!("{:?}", event))
+//!     .spawn()?;
+//! println!("Listening on {:?}", handle.local_addrs());
+//! handle.shutdown();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec;
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fs, io,
+    net::{IpAddr, SocketAddr},
     thread,
 };
 
-use prompted::input;
-use rust_irc::buf_helpers::{one_op_buf, one_param_buf, three_param_buf, two_op_buf};
-use rust_irc::{clear, codes, DEFAULT_PORT};
+use crate::accounts::{self, Accounts};
+use crate::framing::AsyncFrameReader;
+use crate::protocol::{read_message_async, send_async, ErrorCode, MembershipChange, Message};
+use crate::tls;
+use crate::transport::AsyncStream;
+use crate::{capabilities, Error, DEFAULT_PORT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+const SERVER_NAME: &str = "rust-irc";
+/// Optional protocol features this server supports, announced in the version handshake
+const SERVER_CAPABILITIES: &[&str] = &[
+    capabilities::PRIVATE_MESSAGES,
+    capabilities::OPERATORS,
+    capabilities::TOPICS,
+    capabilities::NICK_CHANGES,
+    capabilities::MEMBERSHIP_EVENTS,
+    capabilities::HISTORY,
+    capabilities::PERSISTENT_ROOMS,
+    capabilities::ACCOUNTS,
+];
 
-struct Server {
-    users: HashMap<String, TcpStream>,
-    rooms: HashMap<String, Vec<String>>,
+/// Settings for the server, normally taken from the command line
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Socket addresses to listen on, such as `0.0.0.0:6667` or `[::1]:6667`
+    pub listen: Vec<String>,
+    /// How many messages may wait to be written to one client
+    pub queue_len: usize,
+    /// What happens to a client whose outbound queue is full
+    pub overflow: OverflowPolicy,
+    /// A client silent this long is sent a `KEEP_ALIVE` probe
+    pub keepalive_interval: Duration,
+    /// A client silent this long is disconnected, as is a connection which has not registered in this long
+    pub idle_timeout: Duration,
+    /// Most users which may be registered at once. None for no limit
+    pub max_users: Option<usize>,
+    /// Most connections which may be open without having registered a nickname yet. None for no limit
+    pub max_pending: Option<usize>,
+    /// Most connections which may be open from one IP address. None for no limit
+    pub max_per_ip: Option<usize>,
+    /// How many recent messages each room keeps. 0 keeps none
+    pub history_len: usize,
+    /// How many of a room's recent messages are sent to a user who joins it
+    pub history_on_join: usize,
+    /// Where persistent rooms are saved, and loaded from on start. None to keep them only until the server stops
+    pub data_dir: Option<PathBuf>,
+    /// Save the recent messages of persistent rooms along with their topic, bans and operators
+    pub persist_history: bool,
+    /// PEM file with the certificate chain to serve TLS with. Connections are plain TCP unless this and `tls_key` are set
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of `tls_cert`
+    pub tls_key: Option<PathBuf>,
 }
 
-impl Server {
-    fn new() -> Self {
-        Server {
-            users: HashMap::new(),
-            rooms: HashMap::new(),
+/// What to do when a client is not reading its messages fast enough to keep its queue from filling
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop new messages for the client until its queue has room again
+    Drop,
+    /// Close the client's connection
+    Disconnect,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![format!("0.0.0.0:{}", DEFAULT_PORT)],
+            queue_len: 256,
+            overflow: OverflowPolicy::Disconnect,
+            keepalive_interval: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(30),
+            max_users: None,
+            max_pending: None,
+            max_per_ip: None,
+            history_len: 100,
+            history_on_join: 10,
+            data_dir: None,
+            persist_history: false,
+            tls_cert: None,
+            tls_key: None,
         }
     }
 }
 
-fn message_room(room: &str, msg: &str, sender: &str, server: &Arc<Mutex<Server>>) {
-    let out_buf: Vec<u8> = three_param_buf(codes::MESSAGE_ROOM, room, sender, msg);
-    let mut guard: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    let server: &mut Server = guard.deref_mut();
+/// The file in the data directory which persistent rooms are saved in
+const ROOMS_FILE: &str = "rooms.txt";
 
-    //1: Make sure specified rooms exists, if not -> error
-    //2: Make sure sender is a member of the room, if not -> error
-    //3: Message all non-sender users in the room the message, if none -> error empty room
-    //4: Message the sender RESPONSE_OK
-    let room_users: Option<&Vec<String>> = server.rooms.get(room);
-    let mut sender_stream = server
-        .users
-        .get_mut(sender)
-        .unwrap()
-        .try_clone()
-        .expect("Clone issue");
-    match room_users {
-        Some(users) => {
-            let mut is_member: bool = false;
-            for user in users {
-                if user.eq(sender) {
-                    is_member = true;
+/// A write blocked this long on a client that stopped reading fails, ending its writer
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The state shared by every connection. Each part has its own lock, so that reading one does not wait on writing another.
+/// Locks are taken in the order the fields are declared: `users`, then `rooms`, then one room's lock,
+/// then one user's set of rooms. `accounts` and `connections` are only held alone, or last
+struct State {
+    users: RwLock<Users>,
+    /// The lock on the map is only written to add or remove a room. Changing a room only takes that room's lock
+    rooms: RwLock<HashMap<String, Mutex<Room>>>,
+    accounts: Mutex<Accounts>,
+    connections: Mutex<Connections>,
+    queue_len: usize,
+    overflow: OverflowPolicy,
+    max_users: Option<usize>,
+    max_pending: Option<usize>,
+    max_per_ip: Option<usize>,
+    /// How long a connection may take over the TLS handshake, and over each message before it registers
+    setup_timeout: Duration,
+    history_len: usize,
+    history_on_join: usize,
+    data_dir: Option<PathBuf>,
+    persist_history: bool,
+    /// Wakes the task which saves the persistent rooms, after one of them changed
+    rooms_changed: Notify,
+    /// Held while writing the rooms file, so that an older copy never replaces a newer one
+    saving: tokio::sync::Mutex<()>,
+    hooks: Vec<Hook>,
+}
+
+/// A function called with every [`Event`]
+type Hook = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Something which happened on the server, passed to the hooks added with [`Server::on_event`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A connection registered a nickname
+    Registered {
+        nick: String,
+        peer: SocketAddr,
+    },
+    /// A registered user's connection closed, and they left every room
+    Disconnected {
+        nick: String,
+    },
+    NickChanged {
+        old: String,
+        new: String,
+    },
+    Joined {
+        nick: String,
+        room: String,
+    },
+    Left {
+        nick: String,
+        room: String,
+    },
+    /// A message was sent to the other members of a room
+    RoomMessage {
+        room: String,
+        sender: String,
+        text: String,
+    },
+}
+
+/// Registered users by nickname
+type Users = HashMap<String, User>;
+
+/// A registered user's connection and the rooms they are in
+#[derive(Debug)]
+struct User {
+    outbox: Outbox,
+    /// The names of the rooms the user is a member of, the reverse of each room's members
+    rooms: Mutex<HashSet<String>>,
+    /// Set when the user is disconnected for being idle, until their connection closes
+    timed_out: AtomicBool,
+    /// Optional protocol features the user's client announced in the version handshake
+    capabilities: HashSet<String>,
+}
+
+/// Counts of open connections, for the server's limits
+#[derive(Debug, Default)]
+struct Connections {
+    /// Connections which have not registered a nickname yet
+    pending: usize,
+    /// Open connections from each IP address, registered or not
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// The read half of a connection, read one message at a time
+type Reader = AsyncFrameReader<ReadHalf<AsyncStream>>;
+/// The write half of a connection
+type Writer = WriteHalf<AsyncStream>;
+
+/// How far a connection has been closed, which the tasks reading and writing it watch for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Closing {
+    Open,
+    /// Stop reading, while the messages already queued are still written
+    StopReading,
+    /// Stop reading and writing straight away
+    Close,
+}
+
+/// The bounded queue of messages waiting to be sent to one client, along with the rest of the state of its connection.
+/// A writer task per connection drains it, so nothing waits on a slow client while holding the `State` lock
+#[derive(Clone)]
+struct Outbox {
+    queue: mpsc::Sender<Message>,
+    peer: SocketAddr,
+    policy: OverflowPolicy,
+    closing: Arc<watch::Sender<Closing>>,
+    /// When the user last sent anything, for the idle timeout.
+    /// Kept per user, so that reading a message does not need the `State` lock
+    last_seen: Arc<Mutex<Instant>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+/// A room's members, its topic and its moderation state
+#[derive(Debug, Default)]
+struct Room {
+    /// Each member, with the number of joins to the room before theirs, to tell who has been in it longest
+    members: HashMap<String, u64>,
+    joins: u64,
+    operators: HashSet<String>,
+    banned: HashSet<String>,
+    /// Empty when no topic has been set
+    topic: String,
+    /// Recent messages, oldest first
+    history: VecDeque<HistoryLine>,
+    /// Kept when its last member leaves, and saved to the data directory
+    persistent: bool,
+}
+
+/// One message sent to a room, kept for users who join later
+#[derive(Debug)]
+struct HistoryLine {
+    /// Seconds since the Unix epoch
+    time: u64,
+    sender: String,
+    text: String,
+}
+
+impl Room {
+    /// A new room, whose first member becomes its operator
+    fn new(founder: &str) -> Self {
+        let mut room: Room = Room::default();
+        room.add_member(founder);
+        room.operators.insert(founder.to_string());
+        room
+    }
+
+    fn add_member(&mut self, user: &str) {
+        self.members.insert(user.to_string(), self.joins);
+        self.joins += 1;
+        self.promote_if_unmoderated();
+    }
+
+    /// If none of the room's operators are in it, such as when the operators a persistent room kept
+    /// have not come back, promote the longest standing member
+    fn promote_if_unmoderated(&mut self) {
+        if self
+            .operators
+            .iter()
+            .any(|op: &String| self.members.contains_key(op))
+        {
+            return;
+        }
+        let first: Option<String> = self
+            .members_in_order()
+            .first()
+            .map(|u: &&String| u.to_string());
+        if let Some(first) = first {
+            self.operators.insert(first);
+        }
+    }
+
+    /// Remember a message sent to the room, forgetting the oldest once `capacity` are kept
+    fn record(&mut self, sender: &str, text: &str, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        while self.history.len() >= capacity {
+            self.history.pop_front();
+        }
+        let time: u64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d: Duration| d.as_secs())
+            .unwrap_or(0);
+        self.history.push_back(HistoryLine {
+            time,
+            sender: sender.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    /// Up to `count` of the most recent messages sent to the room called `name`, oldest first
+    fn recent_history(&self, name: &str, count: usize) -> Vec<Message> {
+        let skip: usize = self.history.len().saturating_sub(count);
+        self.history
+            .iter()
+            .skip(skip)
+            .map(|line: &HistoryLine| Message::History {
+                room: name.to_string(),
+                time: line.time,
+                sender: line.sender.to_string(),
+                text: line.text.to_string(),
+            })
+            .collect()
+    }
+
+    fn has_member(&self, user: &str) -> bool {
+        self.members.contains_key(user)
+    }
+
+    fn is_operator(&self, user: &str) -> bool {
+        self.operators.contains(user)
+    }
+
+    /// The members, longest standing first
+    fn members_in_order(&self) -> Vec<&String> {
+        let mut members: Vec<(&String, &u64)> = self.members.iter().collect();
+        members.sort_by_key(|(_, joined): &(&String, &u64)| **joined);
+        members
+            .into_iter()
+            .map(|(member, _): (&String, &u64)| member)
+            .collect()
+    }
+
+    /// Queue a message for every member, except `except` if given
+    fn send(&self, users: &Users, message: &Message, except: Option<&str>) {
+        for member in self.members.keys() {
+            if Some(member.as_str()) == except {
+                continue;
+            }
+            if let Some(user) = users.get(member) {
+                user.notify(message);
+            }
+        }
+    }
+
+    /// Record a message from `sender` to the room called `name`, then send it to every other member.
+    /// Every way of messaging a room goes through here, so that history and delivery always agree
+    fn post(&mut self, users: &Users, name: &str, sender: &str, text: &str, capacity: usize) {
+        self.record(sender, text, capacity);
+        let message: Message = Message::MessageRoom {
+            room: name.to_string(),
+            sender: sender.to_string(),
+            text: text.to_string(),
+        };
+        self.send(users, &message, Some(sender));
+    }
+
+    /// Tell the members of a room called `name`, other than `nick`, that `nick` joined or left it
+    fn notify_members(&self, users: &Users, name: &str, nick: &str, change: MembershipChange) {
+        let event: Message = Message::Membership {
+            room: name.to_string(),
+            nick: nick.to_string(),
+            change,
+        };
+        self.send(users, &event, Some(nick));
+    }
+
+    /// Whether the room should be dropped, because it is empty and not persistent
+    fn is_abandoned(&self) -> bool {
+        self.members.is_empty() && !self.persistent
+    }
+
+    /// Remove a member and any operator status they had, which a persistent room keeps for when they return.
+    /// If that leaves no operator in the room, the longest standing member is promoted.
+    /// Returns whether the user was a member
+    fn remove_member(&mut self, user: &str) -> bool {
+        let was_member: bool = self.members.remove(user).is_some();
+        if !self.persistent {
+            self.operators.remove(user);
+        }
+        self.promote_if_unmoderated();
+        was_member
+    }
+
+    /// Remove a member along with their operator status, which even a persistent room does not keep for them.
+    /// Returns whether the user was a member
+    fn kick(&mut self, user: &str) -> bool {
+        if !self.has_member(user) {
+            return false;
+        }
+        self.operators.remove(user);
+        self.remove_member(user)
+    }
+
+    /// Keep a user out of the room, removing them and their operator status if they are in it.
+    /// Returns whether they were in it
+    fn ban(&mut self, user: &str) -> bool {
+        self.banned.insert(user.to_string());
+        self.operators.remove(user);
+        self.remove_member(user)
+    }
+}
+
+/// The capability a client must announce to be sent a message it did not ask for, if it needs one
+fn required_capability(message: &Message) -> Option<&'static str> {
+    match message {
+        Message::Membership { .. } => Some(capabilities::MEMBERSHIP_EVENTS),
+        Message::History { .. } => Some(capabilities::HISTORY),
+        Message::Persist { .. } => Some(capabilities::PERSISTENT_ROOMS),
+        Message::ChangeNick { .. } => Some(capabilities::NICK_CHANGES),
+        Message::SetTopic { .. } => Some(capabilities::TOPICS),
+        Message::Kick { .. }
+        | Message::Ban { .. }
+        | Message::Unban { .. }
+        | Message::Op { .. }
+        | Message::Deop { .. } => Some(capabilities::OPERATORS),
+        _ => None,
+    }
+}
+
+impl User {
+    fn new(outbox: Outbox, capabilities: HashSet<String>) -> Self {
+        User {
+            outbox,
+            rooms: Mutex::new(HashSet::new()),
+            timed_out: AtomicBool::new(false),
+            capabilities,
+        }
+    }
+
+    /// Whether the user's client announced an optional protocol feature
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Queue a message the user did not ask for, unless it belongs to a feature their client did not announce,
+    /// since the client could not decode it
+    fn notify(&self, message: &Message) {
+        if required_capability(message).is_none_or(|capability: &str| self.supports(capability)) {
+            self.outbox.push(message.clone());
+        }
+    }
+
+    /// The names of the user's rooms, sorted and separated by commas
+    fn room_list(&self) -> String {
+        let mut rooms: Vec<String> = lock(&self.rooms).iter().cloned().collect();
+        rooms.sort();
+        rooms.join(",")
+    }
+}
+
+impl State {
+    fn new(config: &Config) -> Self {
+        State {
+            users: RwLock::new(HashMap::new()),
+            rooms: RwLock::new(HashMap::new()),
+            accounts: Mutex::new(Accounts::default()),
+            connections: Mutex::new(Connections::default()),
+            queue_len: config.queue_len,
+            overflow: config.overflow,
+            max_users: config.max_users,
+            max_pending: config.max_pending,
+            max_per_ip: config.max_per_ip,
+            setup_timeout: config.idle_timeout,
+            history_len: config.history_len,
+            history_on_join: config.history_on_join,
+            data_dir: config.data_dir.clone(),
+            persist_history: config.persist_history,
+            rooms_changed: Notify::new(),
+            saving: tokio::sync::Mutex::new(()),
+            hooks: vec![],
+        }
+    }
+
+    /// Call every hook with the event. Must not be called while holding any of the locks, since hooks may read the server
+    fn emit(&self, event: Event) {
+        for hook in &self.hooks {
+            hook(&event);
+        }
+    }
+
+    /// Have the persistent rooms saved soon, by the task running [`persist_rooms`].
+    /// Changes made before the save starts are all written at once
+    fn save_rooms(&self) {
+        if self.data_dir.is_some() {
+            self.rooms_changed.notify_one();
+        }
+    }
+
+    /// Drop a room if it has become empty and is not persistent.
+    /// Checked again under the write lock, since someone may have joined it since it was last locked
+    fn drop_if_abandoned(&self, name: &str) {
+        let mut rooms: RwLockWriteGuard<'_, HashMap<String, Mutex<Room>>> = write(&self.rooms);
+        if rooms
+            .get(name)
+            .is_some_and(|room: &Mutex<Room>| lock(room).is_abandoned())
+        {
+            rooms.remove(name);
+        }
+    }
+
+    /// Whether as many users as allowed are already registered
+    fn is_full(&self, users: &Users) -> bool {
+        self.max_users.is_some_and(|max: usize| users.len() >= max)
+    }
+
+    /// Count a new connection from the address, unless that would go over one of the limits.
+    /// Returns whether the connection was admitted
+    fn admit(&self, ip: IpAddr) -> bool {
+        let full: bool = self.is_full(&read(&self.users));
+        let mut connections: MutexGuard<'_, Connections> = lock(&self.connections);
+        let from_ip: usize = connections.per_ip.get(&ip).copied().unwrap_or(0);
+        if full
+            || self
+                .max_pending
+                .is_some_and(|max: usize| connections.pending >= max)
+            || self.max_per_ip.is_some_and(|max: usize| from_ip >= max)
+        {
+            return false;
+        }
+        connections.pending += 1;
+        connections.per_ip.insert(ip, from_ip + 1);
+        true
+    }
+
+    /// Stop counting a connection which has closed
+    fn release(&self, ip: IpAddr, registered: bool) {
+        let mut connections: MutexGuard<'_, Connections> = lock(&self.connections);
+        if !registered {
+            connections.pending -= 1;
+        }
+        if let Some(count) = connections.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Write the persistent rooms as text, one `key value` line per setting, each room starting with a `room` line.
+/// Values are escaped so that they cannot break a line, and rooms and names are sorted so that saving twice gives the same file
+fn format_rooms(rooms: &HashMap<String, Mutex<Room>>, with_history: bool) -> String {
+    let mut names: Vec<&String> = rooms.keys().collect();
+    names.sort();
+    let mut text: String = String::new();
+    for name in names {
+        let room: MutexGuard<'_, Room> = lock(&rooms[name]);
+        if !room.persistent {
+            continue;
+        }
+        text.push_str(&format!("room {}\n", escape(name)));
+        if !room.topic.is_empty() {
+            text.push_str(&format!("topic {}\n", escape(&room.topic)));
+        }
+        let mut operators: Vec<&String> = room.operators.iter().collect();
+        operators.sort();
+        for operator in operators {
+            text.push_str(&format!("operator {}\n", escape(operator)));
+        }
+        let mut banned: Vec<&String> = room.banned.iter().collect();
+        banned.sort();
+        for banned in banned {
+            text.push_str(&format!("banned {}\n", escape(banned)));
+        }
+        if with_history {
+            for line in &room.history {
+                let sender: String = escape(&line.sender);
+                let text_escaped: String = escape(&line.text);
+                text.push_str(&format!(
+                    "history {} {} {}\n",
+                    line.time, sender, text_escaped
+                ));
+            }
+        }
+    }
+    text
+}
+
+/// Read rooms written by [`format_rooms`], keeping at most `history_len` messages for each.
+/// Every room read is persistent and has no members yet
+fn parse_rooms(text: &str, history_len: usize) -> Result<HashMap<String, Room>, String> {
+    let mut rooms: HashMap<String, Room> = HashMap::new();
+    let mut current: Option<String> = None;
+    for (number, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let bad_line = || format!("line {}: {}", number + 1, line);
+        let (key, value) = line.split_once(' ').ok_or_else(bad_line)?;
+        if key == "room" {
+            let name: String = unescape(value);
+            rooms.insert(
+                name.to_string(),
+                Room {
+                    persistent: true,
+                    ..Room::default()
+                },
+            );
+            current = Some(name);
+            continue;
+        }
+        let room: &mut Room = current
+            .as_ref()
+            .and_then(|name: &String| rooms.get_mut(name))
+            .ok_or_else(bad_line)?;
+        match key {
+            "topic" => room.topic = unescape(value),
+            "operator" => {
+                room.operators.insert(unescape(value));
+            }
+            "banned" => {
+                room.banned.insert(unescape(value));
+            }
+            "history" => {
+                let mut words: std::str::SplitN<'_, char> = value.splitn(3, ' ');
+                let time: u64 = words
+                    .next()
+                    .and_then(|w: &str| w.parse::<u64>().ok())
+                    .ok_or_else(bad_line)?;
+                let sender: String = unescape(words.next().ok_or_else(bad_line)?);
+                let text: String = unescape(words.next().ok_or_else(bad_line)?);
+                room.history.push_back(HistoryLine { time, sender, text });
+                while room.history.len() > history_len {
+                    room.history.pop_front();
                 }
             }
+            _ => return Err(bad_line()),
+        }
+    }
+    Ok(rooms)
+}
 
-            if is_member {
-                for user in users {
-                    if user.eq(sender) {
-                        sender_stream
-                            .write_all(&one_op_buf(codes::RESPONSE_OK))
-                            .unwrap();
-                    } else {
-                        let recipient_stream: Option<&mut TcpStream> = server.users.get_mut(user);
-                        match recipient_stream {
-                            Some(str) => {
-                                str.write_all(&out_buf).unwrap();
-                            }
-                            None => {
-                                eprintln!("Server error: could not find user");
-                            }
-                        }
+/// Escape backslashes and line breaks, so that a value fits on one line of the rooms file
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out: String = String::new();
+    let mut chars: std::str::Chars<'_> = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Read the persistent rooms saved in the data directory. None if nothing has been saved yet
+fn load_rooms(dir: &Path, history_len: usize) -> Result<Option<HashMap<String, Room>>, String> {
+    let path: PathBuf = dir.join(ROOMS_FILE);
+    let text: String = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    parse_rooms(&text, history_len)
+        .map(Some)
+        .map_err(|e: String| format!("{}: {}", path.display(), e))
+}
+
+impl std::fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Outbox")
+            .field("peer", &self.peer)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl Outbox {
+    /// Start the writer task for a connection. Messages are written in the order they were queued,
+    /// and the connection is shut down once a `Quit` has been written
+    fn new(writer: Writer, peer: SocketAddr, capacity: usize, policy: OverflowPolicy) -> Self {
+        let (queue, pending) = mpsc::channel::<Message>(capacity);
+        let closing: Arc<watch::Sender<Closing>> = Arc::new(watch::Sender::new(Closing::Open));
+        let writer: JoinHandle<()> =
+            tokio::spawn(write_messages(writer, pending, Arc::clone(&closing)));
+        Outbox {
+            queue,
+            peer,
+            policy,
+            closing,
+            last_seen: Arc::new(Mutex::new(Instant::now())),
+            writer: Arc::new(Mutex::new(Some(writer))),
+        }
+    }
+
+    /// Queue a message without waiting, applying the overflow policy if the queue is full.
+    /// Returns whether the message was queued
+    fn push(&self, message: Message) -> bool {
+        match self.queue.try_send(message) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_message)) => {
+                match self.policy {
+                    OverflowPolicy::Drop => {
+                        #[cfg(debug_assertions)]
+                        println!("Outbound queue full, dropped {:x?}", _message.opcode());
+                    }
+                    OverflowPolicy::Disconnect => {
+                        #[cfg(debug_assertions)]
+                        println!("Outbound queue full, disconnecting");
+                        self.close();
                     }
                 }
+                false
+            }
+            // The writer has already stopped because the connection is gone
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Shut the connection down, which also ends its reading task
+    fn close(&self) {
+        self.closing.send_replace(Closing::Close);
+    }
+
+    /// Stop reading from the connection, which ends its task and removes the user,
+    /// while messages already queued are still written
+    fn stop_reading(&self) {
+        self.closing.send_if_modified(|closing: &mut Closing| {
+            let open: bool = *closing == Closing::Open;
+            if open {
+                *closing = Closing::StopReading;
+            }
+            open
+        });
+    }
+
+    /// Note that the user has just sent something
+    fn seen(&self) {
+        *self
+            .last_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
+
+    /// How long the user has been silent for
+    fn idle(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(
+            *self
+                .last_seen
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Wait for the writer task to finish, such as after queueing a `Quit`
+    async fn join(&self) {
+        let writer: Option<JoinHandle<()>> = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(writer) = writer {
+            let _ = writer.await;
+        }
+    }
+}
+
+/// Write a connection's queued messages until a `Quit` has been written, a write fails or the connection is closed.
+/// The connection is then shut down, which also ends the task reading it
+async fn write_messages(
+    mut writer: Writer,
+    mut pending: mpsc::Receiver<Message>,
+    closing: Arc<watch::Sender<Closing>>,
+) {
+    let mut watching: watch::Receiver<Closing> = closing.subscribe();
+    loop {
+        let message: Message = tokio::select! {
+            message = pending.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = watching.wait_for(|c: &Closing| *c == Closing::Close) => break,
+        };
+        // A write which fails, or blocks too long on a client that stopped reading, means the client is gone
+        let written: Result<std::io::Result<()>, tokio::time::error::Elapsed> =
+            tokio::time::timeout(WRITE_TIMEOUT, send_async(&mut writer, &message)).await;
+        if !matches!(written, Ok(Ok(()))) || message == Message::Quit {
+            break;
+        }
+    }
+    closing.send_replace(Closing::Close);
+    let _ = writer.shutdown().await;
+}
+
+/// Lock part of the server state. A task which panicked while holding the lock leaves it poisoned,
+/// but the state is still usable, so one bad connection cannot take the whole server down
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Share part of the server state with other readers, recovering it from a panic just like [`lock`]
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lock part of the server state against every other reader and writer, recovering it from a panic just like [`lock`]
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns whether the message was sent
+fn message_room(room: &str, msg: &str, sender: &str, server: &Arc<State>) -> bool {
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    let sender_outbox: &Outbox = match users.get(sender) {
+        Some(user) => &user.outbox,
+        None => return false,
+    };
+
+    //1: Make sure specified rooms exists, if not -> error
+    //2: Make sure sender is a member of the room, if not -> error
+    //3: Record the message and send it to all non-sender users in the room
+    //4: Message the sender RESPONSE_OK
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    match rooms.get(room) {
+        Some(r) => {
+            let mut r: MutexGuard<'_, Room> = lock(r);
+            if r.has_member(sender) {
+                r.post(&users, room, sender, msg, server.history_len);
+                sender_outbox.push(Message::ResponseOk);
+                true
             } else {
-                sender_stream
-                    .write_all(&two_op_buf(codes::ERROR, codes::error::NOT_IN_ROOM))
-                    .unwrap();
+                sender_outbox.push(Message::Error(ErrorCode::NotInRoom));
+                false
             }
         }
         None => {
-            sender_stream
-                .write_all(&two_op_buf(codes::ERROR, codes::error::EMPTY_ROOM))
-                .unwrap();
+            sender_outbox.push(Message::Error(ErrorCode::EmptyRoom));
+            false
         }
     }
 }
 
-fn broadcast(op: u8, server: &Arc<Mutex<Server>>, message: &str) {
-    let out_buf: Vec<u8> = one_param_buf(op, message);
-    let mut unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    let streams: std::collections::hash_map::ValuesMut<'_, String, TcpStream> =
-        unlocked_server.users.values_mut();
-    for stream in streams {
-        stream.write_all(&out_buf).unwrap();
+fn broadcast(server: &Arc<State>, message: &Message) {
+    for user in read(&server.users).values() {
+        user.outbox.push(message.clone());
     }
 }
 
-fn disconnect_all(server: &Arc<Mutex<Server>>) {
-    let mut guard: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    let users: std::collections::hash_map::ValuesMut<'_, String, TcpStream> =
-        guard.users.values_mut();
-    users.for_each(|user: &mut TcpStream| {
-        user.write_all(&one_op_buf(codes::QUIT)).unwrap();
-        user.shutdown(std::net::Shutdown::Both).unwrap();
+/// Send every client a `Quit`, then wait for their queues to be written out
+async fn disconnect_all(server: &Arc<State>) {
+    let outboxes: Vec<Outbox> = read(&server.users)
+        .values()
+        .map(|user: &User| user.outbox.clone())
+        .collect();
+    for outbox in &outboxes {
+        if !outbox.push(Message::Quit) {
+            outbox.close();
+        }
+    }
+    for outbox in &outboxes {
+        outbox.join().await;
+    }
+}
+
+/// Disconnect every client, then save the persistent rooms with everything they have collected since the last save
+async fn shutdown(server: &Arc<State>) {
+    println!("Stopping Server");
+    disconnect_all(server).await;
+    write_rooms(server).await;
+}
+
+/// Write every persistent room to the data directory, if there is one.
+/// The rooms are copied under their locks, then written on the blocking pool so that no worker waits on the disk.
+/// A complete copy is renamed over the old file, so a crash cannot leave it half written.
+/// Takes the rooms' lock and then each room's lock in turn, so must not be called while holding either
+async fn write_rooms(server: &Arc<State>) {
+    let dir: PathBuf = match &server.data_dir {
+        Some(dir) => dir.clone(),
+        None => return,
+    };
+    let _saving: tokio::sync::MutexGuard<'_, ()> = server.saving.lock().await;
+    let text: String = format_rooms(&read(&server.rooms), server.persist_history);
+    let path: PathBuf = dir.join(ROOMS_FILE);
+    let partial: PathBuf = dir.join(format!("{}.tmp", ROOMS_FILE));
+    let written: io::Result<()> = tokio::task::spawn_blocking(move || {
+        fs::write(&partial, text).and_then(|_| fs::rename(&partial, &path))
     })
+    .await
+    .unwrap_or_else(|e: tokio::task::JoinError| Err(io::Error::other(e)));
+    if let Err(e) = written {
+        eprintln!(
+            "Failed to save rooms to {}: {}",
+            dir.join(ROOMS_FILE).display(),
+            e
+        );
+    }
+}
+
+/// Save the persistent rooms whenever [`State::save_rooms`] asks for it
+async fn persist_rooms(server: Arc<State>) {
+    loop {
+        server.rooms_changed.notified().await;
+        write_rooms(&server).await;
+    }
 }
 /// Handle possible user commands from the client
-fn handle_client(
-    server: &Arc<Mutex<Server>>,
-    stream: &mut TcpStream,
-    nickname: &str,
-    cmd_bytes: &[u8],
-    param_bytes: &[u8],
-) {
-    match cmd_bytes[0] {
-        codes::REGISTER_NICK => {
-            let buf_out: [u8; 2] = two_op_buf(codes::ERROR, codes::error::ALREADY_REGISTERED);
-            stream.write_all(&buf_out).unwrap();
+fn handle_client(server: &Arc<State>, outbox: &Outbox, nickname: &str, message: Message) {
+    match message {
+        Message::JoinServer { .. } | Message::RegisterNick { .. } => {
+            outbox.push(Message::Error(ErrorCode::AlreadyRegistered));
         }
-        codes::LIST_ROOMS => {
-            let unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-            let mut rooms: String = String::new();
-            for room in unlocked_server.rooms.keys() {
-                rooms.push_str(room);
-                rooms.push(' ');
+        Message::ListRooms => {
+            // Clients which know about topics get one room per line with its topic, others just the names
+            let topics: bool = read(&server.users)
+                .get(nickname)
+                .is_some_and(|user: &User| user.supports(capabilities::TOPICS));
+            let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+            let mut names: Vec<&String> = rooms.keys().collect();
+            names.sort();
+            let mut list: String = String::new();
+            for name in names {
+                let topic: String = lock(&rooms[name]).topic.to_string();
+                if !topics {
+                    list.push_str(name);
+                    list.push(' ');
+                } else if topic.is_empty() {
+                    list.push_str(&format!("{}\n", name));
+                } else {
+                    list.push_str(&format!("{}: {}\n", name, topic));
+                }
             }
-            let buf_out: Vec<u8> = one_param_buf(codes::RESPONSE, &rooms);
-            stream.write_all(&buf_out).unwrap();
+            outbox.push(Message::Response(list.trim_end_matches('\n').to_string()));
         }
 
-        codes::LIST_USERS => {
-            let unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
+        Message::ListUsers => {
             let mut users: String = String::new();
-            for user in unlocked_server.users.keys() {
+            for user in read(&server.users).keys() {
                 users.push_str(user);
                 users.push(' ');
             }
-            let buf_out: Vec<u8> = one_param_buf(codes::RESPONSE, &users);
-            stream.write_all(&buf_out).unwrap();
+            outbox.push(Message::Response(users));
         }
 
-        codes::LIST_USERS_IN_ROOM => {
-            let room: String = String::from_utf8_lossy(param_bytes).to_string();
-            let unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-            match unlocked_server.rooms.get(&room) {
-                Some(l) => {
-                    let mut user_list: String = String::new();
-                    for user in l {
-                        user_list.push_str(user);
-                        user_list.push(' ');
+        Message::ListUsersInRoom { room } => match read(&server.rooms).get(&room) {
+            Some(l) => {
+                let l: MutexGuard<'_, Room> = lock(l);
+                let mut user_list: String = String::new();
+                for user in l.members_in_order() {
+                    if l.is_operator(user) {
+                        user_list.push('@');
                     }
-                    let buf_out: Vec<u8> = one_param_buf(codes::RESPONSE, &user_list);
-                    stream.write_all(&buf_out).unwrap();
-                }
-                None => {
-                    let buf_out: [u8; 2] = two_op_buf(codes::ERROR, codes::error::INVALID_ROOM);
-                    stream.write_all(&buf_out).unwrap();
+                    user_list.push_str(user);
+                    user_list.push(' ');
                 }
+                outbox.push(Message::Response(user_list));
             }
-        }
+            None => {
+                outbox.push(Message::Error(ErrorCode::InvalidRoom));
+            }
+        },
 
-        codes::JOIN_ROOM => {
-            let p: String = String::from_utf8_lossy(param_bytes).to_string();
-            let params: Vec<&str> = p.split_whitespace().collect();
-            let room = params.first().unwrap();
-            join_room(server, nickname, room, stream);
+        Message::JoinRoom { room } => {
+            let joined: bool = join_room(server, nickname, &room, outbox);
+            if joined {
+                let nick: String = nickname.to_string();
+                server.emit(Event::Joined { nick, room });
+            }
         }
 
-        codes::LEAVE_ROOM => {
-            let p: String = String::from_utf8_lossy(param_bytes).to_string();
-            let params: Vec<&str> = p.split_whitespace().collect();
-            let room = params.first().unwrap();
-            leave_room(server, nickname, room, stream);
+        Message::LeaveRoom { room } => {
+            let left: bool = leave_room(server, nickname, &room, outbox);
+            if left {
+                let nick: String = nickname.to_string();
+                server.emit(Event::Left { nick, room });
+            }
         }
 
         //Generic message sent to all users of all rooms the clients nickname is in, except the client nickname
-        codes::MESSAGE => {
-            let p: String = String::from_utf8_lossy(param_bytes).to_string();
-
-            message_all_senders_rooms(server, nickname, &p, stream);
-            stream.write_all(&one_op_buf(codes::RESPONSE_OK)).unwrap();
+        Message::Message { text } => {
+            for room in message_all_senders_rooms(server, nickname, &text, outbox) {
+                server.emit(Event::RoomMessage {
+                    room,
+                    sender: nickname.to_string(),
+                    text: text.to_string(),
+                });
+            }
+            outbox.push(Message::ResponseOk);
         }
 
-        codes::KEEP_ALIVE => {
-            stream.write_all(&one_op_buf(codes::RESPONSE_OK)).unwrap();
+        Message::KeepAlive => {
+            outbox.push(Message::ResponseOk);
         }
-
-        //A message sent just to the users of the room passed in, except the client nickname
-        codes::MESSAGE_ROOM => {
-            let p: String = String::from_utf8_lossy(param_bytes).to_string();
-            let params: Option<(&str, &str)> = p.split_once(' ');
-            match params {
-                Some((room, msg)) => {
-                    message_room(room, msg, nickname, server);
+        Message::GetHistory { room, count } => {
+            // Never more than half of the client's queue, so that a replay cannot overflow it
+            let count: usize = (count as usize).min(server.queue_len / 2);
+            match read(&server.rooms).get(&room).map(lock) {
+                Some(r) if !r.has_member(nickname) => {
+                    outbox.push(Message::Error(ErrorCode::NotInRoom));
+                }
+                Some(r) if r.history.is_empty() || count == 0 => {
+                    outbox.push(Message::Response(format!("No history for {}", room)));
                 }
-                _ => {
-                    stream
-                        .write_all(&[codes::ERROR, codes::error::MALFORMED])
-                        .unwrap();
+                Some(r) => {
+                    for line in r.recent_history(&room, count) {
+                        outbox.push(line);
+                    }
+                }
+                None => {
+                    outbox.push(Message::Error(ErrorCode::InvalidRoom));
                 }
             }
         }
-        codes::QUIT => {
-            remove_user(server, nickname);
+        //The answer to our own KEEP_ALIVE probe, which has already counted as activity
+        Message::ResponseOk => {}
+
+        //A message sent just to the users of the room passed in, except the client nickname
+        Message::MessageRoom { room, text, .. } => {
+            let sent: bool = message_room(&room, &text, nickname, server);
+            if sent {
+                let sender: String = nickname.to_string();
+                server.emit(Event::RoomMessage { room, sender, text });
+            }
+        }
+        //A message sent directly to one other user
+        Message::PrivateMessage { nick, text } => {
+            private_message(server, nickname, &nick, &text, outbox);
         }
+        Message::Kick { .. }
+        | Message::Ban { .. }
+        | Message::Unban { .. }
+        | Message::Op { .. }
+        | Message::Deop { .. } => {
+            moderate(server, nickname, message, outbox);
+        }
+        Message::SetTopic { room, topic } => {
+            set_topic(server, nickname, &room, &topic, outbox);
+        }
+        Message::Persist { room, persistent } => {
+            set_persistent(server, nickname, &room, persistent, outbox);
+        }
+        Message::GetTopic { room } => match read(&server.rooms).get(&room).map(lock) {
+            Some(r) if r.topic.is_empty() => {
+                let response: String = format!("No topic is set for {}", room);
+                outbox.push(Message::Response(response));
+            }
+            Some(r) => {
+                let response: String = format!("Topic for {}: {}", room, r.topic);
+                outbox.push(Message::Response(response));
+            }
+            None => {
+                outbox.push(Message::Error(ErrorCode::InvalidRoom));
+            }
+        },
         _ => {
             #[cfg(debug_assertions)]
-            println!("Unspecified client Op, {:x?}", cmd_bytes);
+            println!("Unspecified client Op, {:x?}", message.opcode());
         }
     }
-
-    // }
 }
 
 /// Send a message to all of the rooms the given sender has joined.
+/// Returns the rooms it was sent to
 fn message_all_senders_rooms(
-    server: &Arc<Mutex<Server>>,
+    server: &Arc<State>,
     sender: &str,
     message: &str,
-    stream: &mut TcpStream,
+    outbox: &Outbox,
+) -> Vec<String> {
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    // Copied, since a user's rooms are locked after the rooms themselves
+    let joined: Vec<String> = match users.get(sender) {
+        Some(user) => lock(&user.rooms).iter().cloned().collect(),
+        None => return vec![],
+    };
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    let mut sent: Vec<String> = vec![];
+    for room in joined {
+        let mut members: MutexGuard<'_, Room> = match rooms.get(&room) {
+            Some(members) => lock(members),
+            None => continue,
+        };
+        members.post(&users, &room, sender, message, server.history_len);
+        outbox.push(Message::ResponseOk);
+        sent.push(room);
+    }
+    sent
+}
+
+/// Send a message directly to another user, by nickname
+fn private_message(
+    server: &Arc<State>,
+    sender: &str,
+    recipient: &str,
+    message: &str,
+    outbox: &Outbox,
 ) {
-    let rooms: Vec<String> = get_rooms_of_user(server, sender);
-    let mut guard: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    for room in rooms {
-        let users: Vec<String> = guard.rooms.get(&room).unwrap().clone();
-        let out_buf: Vec<u8> = three_param_buf(codes::MESSAGE_ROOM, &room, sender, message);
-        for user in users {
-            if !user.eq(sender) {
-                let stream: Option<&mut TcpStream> = guard.users.get_mut(&user);
-                stream.unwrap().write_all(&out_buf).unwrap();
-            }
+    match read(&server.users).get(recipient) {
+        Some(recipient) => {
+            let out_msg: Message = Message::PrivateMessage {
+                nick: sender.to_string(),
+                text: message.to_string(),
+            };
+            recipient.outbox.push(out_msg);
+            outbox.push(Message::ResponseOk);
+        }
+        None => {
+            outbox.push(Message::Error(ErrorCode::NoSuchUser));
         }
-        stream.write_all(&one_op_buf(codes::RESPONSE_OK)).unwrap();
     }
 }
 
-/// Remove a user from any rooms they may be in, then drop the user. Drop the room if it became empty
-fn remove_user(server: &Arc<Mutex<Server>>, nickname: &str) {
-    let mut guard: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    let server: &mut Server = guard.deref_mut();
-    let rooms: &mut HashMap<String, Vec<String>> = &mut server.rooms;
-    let mut empty_rooms: Vec<String> = vec![];
-    rooms.iter_mut().for_each(|(room, users)| {
-        users.retain(|u: &String| !u.eq(nickname));
-        if users.is_empty() {
-            empty_rooms.push(room.to_string());
+/// Remove a user from any rooms they may be in, then drop the user. Drop the room if it became empty, unless it is persistent.
+/// The remaining members of the user's rooms are told the user quit, or timed out
+fn remove_user(server: &Arc<State>, nickname: &str) {
+    let mut users: RwLockWriteGuard<'_, Users> = write(&server.users);
+    let user: User = match users.remove(nickname) {
+        Some(user) => user,
+        None => return,
+    };
+    let change: MembershipChange = if user.timed_out.load(Ordering::Relaxed) {
+        MembershipChange::TimedOut
+    } else {
+        MembershipChange::Quit
+    };
+    let mut rooms: RwLockWriteGuard<'_, HashMap<String, Mutex<Room>>> = write(&server.rooms);
+    for name in lock(&user.rooms).iter() {
+        let room: &mut Room = match rooms.get_mut(name) {
+            Some(room) => room.get_mut().unwrap_or_else(PoisonError::into_inner),
+            None => continue,
+        };
+        if !room.remove_member(nickname) {
+            continue;
+        }
+        if room.is_abandoned() {
+            rooms.remove(name);
+        } else {
+            room.notify_members(&users, name, nickname, change);
         }
-    });
-    for room in empty_rooms {
-        rooms.remove(&room);
     }
-    let users: &mut HashMap<String, TcpStream> = &mut server.users;
-    users.remove(nickname);
 }
 
-#[test]
-fn test_remove_user() {
-    let socket: Result<TcpListener, std::io::Error> =
-        TcpListener::bind("0.0.0.0:".to_string() + &DEFAULT_PORT.to_string());
-    match socket {
-        Ok(_) => {
-            let stream: Result<TcpStream, std::io::Error> =
-                TcpStream::connect("0.0.0.0:".to_string() + &DEFAULT_PORT.to_string());
-            match stream {
-                Ok(stream) => {
-                    let server_arc: Arc<Mutex<Server>> = Arc::new(Mutex::new(Server::new()));
-                    let mut guard: std::sync::MutexGuard<'_, Server> = server_arc.lock().unwrap();
-                    guard.users.insert("david".to_string(), stream);
-                    guard
-                        .rooms
-                        .insert("cat".to_string(), vec!["david".to_string()]);
-                    assert!(guard.rooms.contains_key("cat"));
-                    assert!(guard.users.contains_key("david"));
-                    drop(guard);
-                    remove_user(&server_arc, "david");
-                    let guard: std::sync::MutexGuard<'_, Server> = server_arc.lock().unwrap();
-                    assert!(guard.rooms.is_empty());
-                    assert!(guard.users.is_empty());
-                }
-                _ => {
-                    eprintln!("Test issue. Check port 6667 is free");
-                }
+/// Every `keepalive` interval, probe users who have been silent that long with a `KEEP_ALIVE`,
+/// and disconnect users who have been silent for longer than `timeout`
+async fn reap_idle_users(server: Arc<State>, keepalive: Duration, timeout: Duration) {
+    loop {
+        tokio::time::sleep(keepalive).await;
+        let now: Instant = Instant::now();
+        for (nickname, user) in read(&server.users).iter() {
+            let idle: Duration = user.outbox.idle(now);
+            if idle > timeout {
+                println!(
+                    "{} has been silent for {} seconds, disconnecting",
+                    nickname,
+                    idle.as_secs()
+                );
+                user.outbox.push(Message::Quit);
+                user.outbox.stop_reading();
+                user.timed_out.store(true, Ordering::Relaxed);
+            } else if idle >= keepalive {
+                user.outbox.push(Message::KeepAlive);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_remove_user() {
+    let server_arc: Arc<State> = Arc::new(State::new(&Config::default()));
+    let (_client, connection) = tokio::io::duplex(1024);
+    let (_, writer) = tokio::io::split(Box::new(connection) as AsyncStream);
+    let peer: SocketAddr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let outbox: Outbox = Outbox::new(writer, peer, 8, OverflowPolicy::Drop);
+    let user: User = User::new(outbox, HashSet::new());
+    lock(&user.rooms).insert("cat".to_string());
+    write(&server_arc.users).insert("david".to_string(), user);
+    write(&server_arc.rooms).insert("cat".to_string(), Mutex::new(Room::new("david")));
+    remove_user(&server_arc, "david");
+    assert!(read(&server_arc.rooms).is_empty());
+    assert!(read(&server_arc.users).is_empty());
+}
+
+/// The client's end of an in-memory connection to the server
+#[cfg(test)]
+type TestClient = (
+    WriteHalf<tokio::io::DuplexStream>,
+    AsyncFrameReader<ReadHalf<tokio::io::DuplexStream>>,
+);
+
+/// Connect a client over an in-memory pipe, served by its own task just like a TCP connection,
+/// and register its nickname
+#[cfg(test)]
+async fn connect_in_memory(server: &Arc<State>, nick: &str) -> TestClient {
+    connect_in_memory_with(server, nick, SERVER_CAPABILITIES).await
+}
+
+/// Connect a client over an in-memory pipe which announces the given capabilities
+#[cfg(test)]
+async fn connect_in_memory_with(
+    server: &Arc<State>,
+    nick: &str,
+    capabilities: &[&str],
+) -> TestClient {
+    let (client, connection) = tokio::io::duplex(64 * 1024);
+    let peer: SocketAddr = SocketAddr::from(([127, 0, 0, 1], 0));
+    assert!(server.admit(peer.ip()));
+    let server_inner: Arc<State> = Arc::clone(server);
+    tokio::spawn(async move { handle_connection(&server_inner, Box::new(connection), peer).await });
+    let (reader, mut writer) = tokio::io::split(client);
+    let mut reader: AsyncFrameReader<ReadHalf<tokio::io::DuplexStream>> =
+        AsyncFrameReader::new(reader);
+    let join: Message = Message::JoinServer {
+        version: PROTOCOL_VERSION,
+        capabilities: capabilities.iter().map(|c: &&str| c.to_string()).collect(),
+    };
+    send_async(&mut writer, &join).await.unwrap();
+    assert!(matches!(
+        read_message_async(&mut reader).await,
+        Ok(Message::ServerInfo { .. })
+    ));
+    let register: Message = Message::RegisterNick {
+        nick: nick.to_string(),
+        password: None,
+    };
+    send_async(&mut writer, &register).await.unwrap();
+    (writer, reader)
+}
+
+#[tokio::test]
+async fn test_room_conversation() {
+    let server: Arc<State> = Arc::new(State::new(&Config::default()));
+    let (mut alice, mut alice_reader) = connect_in_memory(&server, "alice").await;
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::ResponseOk
+    );
+    let (mut bob, mut bob_reader) = connect_in_memory(&server, "bob").await;
+    assert_eq!(
+        read_message_async(&mut bob_reader).await.unwrap(),
+        Message::ResponseOk
+    );
+    let (_imposter, mut imposter_reader) = connect_in_memory(&server, "alice").await;
+    assert_eq!(
+        read_message_async(&mut imposter_reader).await.unwrap(),
+        Message::Error(ErrorCode::NicknameCollision)
+    );
+    assert!(matches!(
+        read_message_async(&mut imposter_reader).await,
+        Err(Error::Closed)
+    ));
+
+    let join: Message = Message::JoinRoom {
+        room: "cat".to_string(),
+    };
+    send_async(&mut alice, &join).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::Response("Joined cat. Current rooms: cat".to_string())
+    );
+    send_async(&mut bob, &join).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut bob_reader).await.unwrap(),
+        Message::Response("Joined cat. Current rooms: cat".to_string())
+    );
+    let joined: Message = Message::Membership {
+        room: "cat".to_string(),
+        nick: "bob".to_string(),
+        change: MembershipChange::Joined,
+    };
+    assert_eq!(read_message_async(&mut alice_reader).await.unwrap(), joined);
+
+    let hello: Message = Message::MessageRoom {
+        room: "cat".to_string(),
+        sender: "alice".to_string(),
+        text: "hello".to_string(),
+    };
+    send_async(&mut alice, &hello).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::ResponseOk
+    );
+    assert_eq!(read_message_async(&mut bob_reader).await.unwrap(), hello);
+    let everywhere: Message = Message::Message {
+        text: "hello all".to_string(),
+    };
+    send_async(&mut bob, &everywhere).await.unwrap();
+    // One for the room, and one once every room has been sent to
+    for _ in 0..2 {
+        assert_eq!(
+            read_message_async(&mut bob_reader).await.unwrap(),
+            Message::ResponseOk
+        );
+    }
+    let hello_all: Message = Message::MessageRoom {
+        room: "cat".to_string(),
+        sender: "bob".to_string(),
+        text: "hello all".to_string(),
+    };
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        hello_all
+    );
+    // Both ways of messaging a room are kept in its history
+    let history: Vec<String> = lock(&read(&server.rooms)["cat"])
+        .history
+        .iter()
+        .map(|line: &HistoryLine| format!("{}: {}", line.sender, line.text))
+        .collect();
+    assert_eq!(history, vec!["alice: hello", "bob: hello all"]);
+
+    send_async(&mut bob, &Message::Quit).await.unwrap();
+    let quit: Message = Message::Membership {
+        room: "cat".to_string(),
+        nick: "bob".to_string(),
+        change: MembershipChange::Quit,
+    };
+    assert_eq!(read_message_async(&mut alice_reader).await.unwrap(), quit);
+    assert!(matches!(
+        read_message_async(&mut bob_reader).await,
+        Err(Error::Closed)
+    ));
+    let members: Vec<String> = lock(&read(&server.rooms)["cat"])
+        .members_in_order()
+        .into_iter()
+        .cloned()
+        .collect();
+    assert_eq!(members, vec!["alice".to_string()]);
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    assert!(!users.contains_key("bob"));
+    assert_eq!(users["alice"].room_list(), "cat");
+}
+
+#[tokio::test]
+async fn test_kick_and_rename_update_membership() {
+    let (sender, events) = std::sync::mpsc::channel::<Event>();
+    let sender: Mutex<std::sync::mpsc::Sender<Event>> = Mutex::new(sender);
+    let mut state: State = State::new(&Config::default());
+    state.hooks.push(Box::new(move |event: &Event| {
+        let _ = lock(&sender).send(event.clone());
+    }));
+    let server: Arc<State> = Arc::new(state);
+    let (mut alice, mut alice_reader) = connect_in_memory(&server, "alice").await;
+    let (mut bob, mut bob_reader) = connect_in_memory(&server, "bob").await;
+    let (mut carol, mut carol_reader) = connect_in_memory(&server, "carol").await;
+    for room in ["cat", "dog"] {
+        let join: Message = Message::JoinRoom {
+            room: room.to_string(),
+        };
+        send_async(&mut alice, &join).await.unwrap();
+        // Founding the room first makes alice its operator
+        while !matches!(
+            read_message_async(&mut alice_reader).await.unwrap(),
+            Message::Response(_)
+        ) {}
+        send_async(&mut bob, &join).await.unwrap();
+    }
+    let join: Message = Message::JoinRoom {
+        room: "cat".to_string(),
+    };
+    send_async(&mut carol, &join).await.unwrap();
+    while !matches!(
+        read_message_async(&mut carol_reader).await.unwrap(),
+        Message::Response(_)
+    ) {}
+    let kick: Message = Message::Kick {
+        room: "cat".to_string(),
+        nick: "bob".to_string(),
+    };
+    send_async(&mut alice, &kick).await.unwrap();
+    loop {
+        if let Message::Response(text) = read_message_async(&mut alice_reader).await.unwrap() {
+            if text.starts_with("Kicked") {
+                break;
             }
         }
-        Err(_) => {
-            eprintln!("Test issue. Check port 6667 is free");
+    }
+    // The members left in the room, and the hooks, see bob leave
+    assert_eq!(
+        read_message_async(&mut carol_reader).await.unwrap(),
+        Message::Membership {
+            room: "cat".to_string(),
+            nick: "bob".to_string(),
+            change: MembershipChange::Left,
+        }
+    );
+    let left: Event = Event::Left {
+        nick: "bob".to_string(),
+        room: "cat".to_string(),
+    };
+    assert!(events.try_iter().any(|event: Event| event == left));
+
+    let rename: Message = Message::ChangeNick {
+        old: "bob".to_string(),
+        nick: "robert".to_string(),
+    };
+    send_async(&mut bob, &rename).await.unwrap();
+    let renamed: Message = Message::ChangeNick {
+        old: "bob".to_string(),
+        nick: "robert".to_string(),
+    };
+    loop {
+        if read_message_async(&mut bob_reader).await.unwrap() == renamed {
+            break;
         }
     }
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    assert_eq!(users["alice"].room_list(), "cat,dog");
+    assert_eq!(users["robert"].room_list(), "dog");
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    assert_eq!(
+        lock(&rooms["cat"]).members_in_order(),
+        vec!["alice", "carol"]
+    );
+    assert_eq!(
+        lock(&rooms["dog"]).members_in_order(),
+        vec!["alice", "robert"]
+    );
+}
+
+#[tokio::test]
+async fn test_notices_follow_capabilities() {
+    let server: Arc<State> = Arc::new(State::new(&Config::default()));
+    let (mut alice, mut alice_reader) = connect_in_memory(&server, "alice").await;
+    let (mut old, mut old_reader) = connect_in_memory_with(&server, "old", &[]).await;
+    for reader in [&mut alice_reader, &mut old_reader] {
+        assert_eq!(
+            read_message_async(reader).await.unwrap(),
+            Message::ResponseOk
+        );
+    }
+    let join: Message = Message::JoinRoom {
+        room: "cat".to_string(),
+    };
+    send_async(&mut alice, &join).await.unwrap();
+    read_message_async(&mut alice_reader).await.unwrap();
+    let hello: Message = Message::MessageRoom {
+        room: "cat".to_string(),
+        sender: "alice".to_string(),
+        text: "hello".to_string(),
+    };
+    send_async(&mut alice, &hello).await.unwrap();
+    read_message_async(&mut alice_reader).await.unwrap();
+
+    // Neither the history replay, nor the notice that it joined, goes to a client which cannot decode them
+    send_async(&mut old, &join).await.unwrap();
+    assert!(matches!(
+        read_message_async(&mut old_reader).await.unwrap(),
+        Message::Response(_)
+    ));
+    assert!(matches!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::Membership { .. }
+    ));
+    let topic: Message = Message::SetTopic {
+        room: "cat".to_string(),
+        topic: "meow".to_string(),
+    };
+    send_async(&mut alice, &topic).await.unwrap();
+    assert_eq!(read_message_async(&mut alice_reader).await.unwrap(), topic);
+    let persist: Message = Message::Persist {
+        room: "cat".to_string(),
+        persistent: true,
+    };
+    send_async(&mut alice, &persist).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        persist
+    );
+    send_async(&mut alice, &hello).await.unwrap();
+    assert_eq!(read_message_async(&mut old_reader).await.unwrap(), hello);
+}
+
+#[tokio::test]
+async fn test_list_rooms_format_follows_capabilities() {
+    let server: Arc<State> = Arc::new(State::new(&Config::default()));
+    let (mut alice, mut alice_reader) = connect_in_memory_with(&server, "alice", &[]).await;
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::ResponseOk
+    );
+    let (mut bob, mut bob_reader) =
+        connect_in_memory_with(&server, "bob", &[capabilities::TOPICS]).await;
+    assert_eq!(
+        read_message_async(&mut bob_reader).await.unwrap(),
+        Message::ResponseOk
+    );
+    for room in ["dog", "cat"] {
+        let join: Message = Message::JoinRoom {
+            room: room.to_string(),
+        };
+        send_async(&mut alice, &join).await.unwrap();
+        read_message_async(&mut alice_reader).await.unwrap();
+    }
+    lock(&read(&server.rooms)["cat"]).topic = "meow".to_string();
+
+    // A client which does not know about topics gets the names it always has
+    send_async(&mut alice, &Message::ListRooms).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::Response("cat dog ".to_string())
+    );
+    send_async(&mut bob, &Message::ListRooms).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut bob_reader).await.unwrap(),
+        Message::Response("cat: meow\ndog".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_names_with_control_characters_are_malformed() {
+    let server: Arc<State> = Arc::new(State::new(&Config::default()));
+    let (_, mut bell_reader) = connect_in_memory(&server, "bell\u{7}").await;
+    assert_eq!(
+        read_message_async(&mut bell_reader).await.unwrap(),
+        Message::Error(ErrorCode::Malformed)
+    );
+    assert!(!read(&server.users).contains_key("bell\u{7}"));
+
+    let (mut alice, mut alice_reader) = connect_in_memory(&server, "alice").await;
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::ResponseOk
+    );
+    let join: Message = Message::JoinRoom {
+        room: "cat\t".to_string(),
+    };
+    send_async(&mut alice, &join).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::Error(ErrorCode::Malformed)
+    );
+    assert!(read(&server.rooms).is_empty());
+    let rename: Message = Message::ChangeNick {
+        old: "alice".to_string(),
+        nick: "al\u{1b}ice".to_string(),
+    };
+    send_async(&mut alice, &rename).await.unwrap();
+    assert_eq!(
+        read_message_async(&mut alice_reader).await.unwrap(),
+        Message::Error(ErrorCode::Malformed)
+    );
+    assert!(read(&server.users).contains_key("alice"));
+}
+
+#[test]
+fn test_room_operator_promotion() {
+    let mut room: Room = Room::new("westy");
+    for user in ["easty", "northy", "southy"] {
+        room.add_member(user);
+    }
+    assert!(room.is_operator("westy"));
+    assert!(!room.is_operator("easty"));
+
+    assert!(room.remove_member("westy"));
+    assert!(!room.has_member("westy"));
+    assert!(room.is_operator("easty"));
+    assert_eq!(room.operators.len(), 1);
+
+    assert!(!room.remove_member("westy"));
+    assert!(room.remove_member("northy"));
+    assert!(room.remove_member("easty"));
+    assert_eq!(room.members_in_order(), vec!["southy"]);
+    assert!(room.is_operator("southy"));
+
+    // A persistent room keeps an operator who leaves, but still promotes someone who is there
+    let mut room: Room = Room::new("westy");
+    room.persistent = true;
+    room.add_member("easty");
+    assert!(room.remove_member("westy"));
+    assert!(room.is_operator("westy") && room.is_operator("easty"));
+
+    // Kicking or banning the only operator present hands the room to the next member, in any room
+    room.add_member("northy");
+    assert!(room.kick("easty"));
+    assert!(!room.is_operator("easty") && room.is_operator("northy"));
+    assert!(!room.kick("easty"));
+    room.add_member("southy");
+    room.operators.remove("westy");
+    room.ban("northy");
+    assert!(room.banned.contains("northy") && !room.is_operator("northy"));
+    assert!(room.is_operator("southy"));
+}
+
+#[test]
+fn test_room_history_is_bounded() {
+    let mut room: Room = Room::new("westy");
+    for text in ["one", "two", "three", "four"] {
+        room.record("westy", text, 3);
+    }
+    let texts: Vec<String> = room
+        .recent_history("cat", 10)
+        .into_iter()
+        .filter_map(|m: Message| match m {
+            Message::History { text, .. } => Some(text),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(texts, vec!["two", "three", "four"]);
+    assert_eq!(room.recent_history("cat", 1).len(), 1);
+    room.record("westy", "five", 0);
+    assert_eq!(room.history.len(), 3);
+}
+
+#[test]
+fn test_persistent_rooms() {
+    let mut room: Room = Room::new("westy");
+    room.persistent = true;
+    room.topic = "cats\nand \\dogs".to_string();
+    room.banned.insert("easty".to_string());
+    room.record("westy", "hello\r\nthere", 10);
+    assert!(room.remove_member("westy"));
+    assert!(!room.is_abandoned());
+    assert!(room.is_operator("westy"));
+
+    let mut rooms: HashMap<String, Mutex<Room>> = HashMap::new();
+    rooms.insert("cat".to_string(), Mutex::new(room));
+    rooms.insert("dog".to_string(), Mutex::new(Room::new("northy")));
+    let loaded: HashMap<String, Room> = parse_rooms(&format_rooms(&rooms, true), 10).unwrap();
+    assert_eq!(loaded.len(), 1);
+    let cat: &Room = &loaded["cat"];
+    assert!(cat.persistent && cat.members.is_empty());
+    assert_eq!(cat.topic, lock(&rooms["cat"]).topic);
+    assert!(cat.is_operator("westy") && cat.operators.len() == 1);
+    assert!(cat.banned.contains("easty") && cat.banned.len() == 1);
+    assert_eq!(cat.history[0].text, "hello\r\nthere");
+    // The saved operator is away, so the first member to join moderates the room
+    let mut cat: Room = parse_rooms(&format_rooms(&rooms, true), 10)
+        .unwrap()
+        .remove("cat")
+        .unwrap();
+    cat.add_member("northy");
+    cat.add_member("southy");
+    assert!(cat.is_operator("northy") && !cat.is_operator("southy"));
+    assert!(cat.is_operator("westy"));
+
+    let without_history: String = format_rooms(&rooms, false);
+    assert!(parse_rooms(&without_history, 10).unwrap()["cat"]
+        .history
+        .is_empty());
+    assert!(parse_rooms("topic orphaned", 10).is_err());
+    assert!(parse_rooms("room cat\nmode +x", 10).is_err());
+}
+
+#[tokio::test]
+async fn test_connection_limits() {
+    use tokio::io::AsyncReadExt;
+
+    let config: Config = Config {
+        max_pending: Some(2),
+        max_per_ip: Some(2),
+        idle_timeout: Duration::from_millis(50),
+        ..Config::default()
+    };
+    let server: Arc<State> = Arc::new(State::new(&config));
+    let local: IpAddr = IpAddr::from([127, 0, 0, 1]);
+    let remote: IpAddr = IpAddr::from([192, 0, 2, 1]);
+    assert!(server.admit(local));
+    assert!(server.admit(local));
+    assert!(!server.admit(local));
+    assert!(!server.admit(remote)); // too many pending
+    server.release(local, false);
+    assert!(server.admit(remote));
+    assert!(!server.admit(local));
+    server.release(local, false);
+    server.release(remote, false);
+
+    // A connection which never says anything is closed, freeing its slots
+    let (mut silent, connection) = tokio::io::duplex(1024);
+    assert!(server.admit(local));
+    handle_connection(&server, Box::new(connection), SocketAddr::new(local, 0)).await;
+    let mut rest: Vec<u8> = vec![];
+    silent.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    let connections: MutexGuard<'_, Connections> = lock(&server.connections);
+    assert_eq!(connections.pending, 0);
+    assert!(connections.per_ip.is_empty());
 }
 
 /// Add a nickname to the Server, being careful to handle a possible collision.
-fn register_nick(server: &Arc<Mutex<Server>>, nickname: &str, stream: &mut TcpStream) {
-    let mut unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    if unlocked_server.users.contains_key(nickname) {
-        #[cfg(debug_assertions)]
-        println!("Nickname Collision, {}", nickname);
-        stream
-            .write_all(&[codes::ERROR, codes::error::NICKNAME_COLLISION])
-            .unwrap();
-    } else {
-        let clone: TcpStream = stream.try_clone().expect("fail to clone");
-        let addr: String = clone.peer_addr().unwrap().to_string();
+/// Returns the outbound queue of the newly registered user, which takes over writing to the connection,
+/// or None if the nickname was taken
+async fn register_nick(
+    server: &Arc<State>,
+    nickname: &str,
+    capabilities: HashSet<String>,
+    mut writer: Writer,
+    peer: SocketAddr,
+) -> crate::Result<Option<Outbox>> {
+    let refusal: ErrorCode = {
+        let mut users: RwLockWriteGuard<'_, Users> = write(&server.users);
+        if users.contains_key(nickname) {
+            #[cfg(debug_assertions)]
+            println!("Nickname Collision, {}", nickname);
+            ErrorCode::NicknameCollision
+        } else if server.is_full(&users) {
+            println!("{} rejected, the server is full", peer);
+            ErrorCode::ServerFull
+        } else {
+            let outbox: Outbox = Outbox::new(writer, peer, server.queue_len, server.overflow);
+            let user: User = User::new(outbox.clone(), capabilities);
+            users.insert(nickname.to_string(), user);
+            lock(&server.connections).pending -= 1;
+            outbox.push(Message::ResponseOk);
+            println!("{} has registered nickname {}", peer, nickname);
+            return Ok(Some(outbox));
+        }
+    };
+    send_async(&mut writer, &Message::Error(refusal)).await?;
+    Ok(None)
+}
 
-        unlocked_server.users.insert(nickname.to_string(), clone);
-        stream.write_all(&one_op_buf(codes::RESPONSE_OK)).unwrap();
-        println!("{} has registered nickname {}", addr, nickname);
+/// Add a member to an existing room, unless they are banned from it or already in it.
+/// Returns the room's topic and the recent messages to send the new member
+fn add_member(
+    server: &State,
+    users: &Users,
+    room: &mut Room,
+    name: &str,
+    user: &str,
+) -> Result<(String, Vec<Message>), ErrorCode> {
+    if room.banned.contains(user) {
+        return Err(ErrorCode::Banned);
+    }
+    if room.has_member(user) {
+        return Err(ErrorCode::AlreadyInRoom);
     }
+    room.add_member(user);
+    room.notify_members(users, name, user, MembershipChange::Joined);
+    Ok((
+        room.topic.to_string(),
+        room.recent_history(name, server.history_on_join),
+    ))
 }
 
 /// Add user to a room, creating the room if necessary
-/// Provide feedback about what room was just joined, and which rooms the user may be in
-fn join_room(server: &Arc<Mutex<Server>>, user: &str, room: &str, stream: &mut TcpStream) {
-    let mut unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    let err_buf: [u8; 2] = two_op_buf(codes::ERROR, codes::error::ALREADY_IN_ROOM);
-
-    match unlocked_server.rooms.get_mut(room) {
-        Some(l) => {
-            for ele in l.iter_mut() {
-                if ele == user {
-                    stream.write_all(&err_buf).unwrap();
-                    return;
+/// Provide feedback about what room was just joined, and which rooms the user may be in.
+/// Returns whether the user joined
+fn join_room(server: &Arc<State>, user: &str, room: &str, outbox: &Outbox) -> bool {
+    // Room names follow the rules for nicknames, so that every client can name the room
+    if !accounts::valid_nick(room) {
+        outbox.push(Message::Error(ErrorCode::Malformed));
+        return false;
+    }
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    let joiner: &User = match users.get(user) {
+        Some(joiner) => joiner,
+        None => return false,
+    };
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    let joined: Result<(String, Vec<Message>), ErrorCode> = match rooms.get(room) {
+        Some(r) => add_member(server, &users, &mut lock(r), room, user),
+        None => {
+            // Only creating a room needs the whole map, and someone else may have created it in the meantime
+            drop(rooms);
+            match write(&server.rooms).entry(room.to_string()) {
+                Entry::Occupied(mut r) => {
+                    let r: &mut Room = r
+                        .get_mut()
+                        .get_mut()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    add_member(server, &users, r, room, user)
+                }
+                Entry::Vacant(r) => {
+                    r.insert(Mutex::new(Room::new(user)));
+                    Ok((String::new(), vec![]))
                 }
             }
-            l.push(user.to_string());
         }
-        None => {
-            let list: Vec<String> = vec![user.to_string()];
-            unlocked_server.rooms.insert(room.to_string(), list);
+    };
+    let (topic, backlog) = match joined {
+        Ok(joined) => joined,
+        Err(e) => {
+            outbox.push(Message::Error(e));
+            return false;
         }
+    };
+    lock(&joiner.rooms).insert(room.to_string());
+    let rooms_expanded: String = joiner.room_list();
+    let response: String = if topic.is_empty() {
+        format!("Joined {}. Current rooms: {}", room, rooms_expanded)
+    } else {
+        format!(
+            "Joined {}. Topic: {}. Current rooms: {}",
+            room, topic, rooms_expanded
+        )
+    };
+    outbox.push(Message::Response(response));
+    for line in &backlog {
+        joiner.notify(line);
     }
-    drop(unlocked_server);
-    let rooms: Vec<String> = get_rooms_of_user(server, user);
-    let rooms_expanded: String = rooms.join(",");
-    let response: String = format!("Joined {}. Current rooms: {}", room, rooms_expanded);
-    let out_buf: Vec<u8> = one_param_buf(codes::RESPONSE, &response);
-    stream.write_all(&out_buf).unwrap();
+    true
 }
 
 /// Remove a user from a room, handling possible error cases.
-/// Provide feedback about what room was just left, and which rooms the user may still be in
-fn leave_room(server: &Arc<Mutex<Server>>, user: &str, room: &str, stream: &mut TcpStream) {
-    let mut unlocked_server: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    match unlocked_server.rooms.get_mut(room) {
+/// Provide feedback about what room was just left, and which rooms the user may still be in.
+/// Returns whether the user left
+fn leave_room(server: &Arc<State>, user: &str, room: &str, outbox: &Outbox) -> bool {
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    let leaver: &User = match users.get(user) {
+        Some(leaver) => leaver,
+        None => return false,
+    };
+    let abandoned: bool = match read(&server.rooms).get(room) {
         Some(l) => {
-            let before_len: usize = l.len();
-            l.retain(|item: &String| item != user);
-
+            let mut l: MutexGuard<'_, Room> = lock(l);
             // case when the user was not found to be in the room.
-            if l.len() == before_len {
-                let err_buf: [u8; 2] = two_op_buf(codes::ERROR, codes::error::NOT_IN_ROOM);
-                stream.write_all(&err_buf).unwrap();
-            } else {
-                if l.is_empty() {
-                    unlocked_server.rooms.remove(room); //drop the room if this was the last member
-                }
-                drop(unlocked_server);
-                let rooms: Vec<String> = get_rooms_of_user(server, user);
-                let rooms_expanded: String = rooms.join(",");
-                let response: String = format!("Left {}. Current rooms: {}", room, rooms_expanded);
-                let out_buf: Vec<u8> = one_param_buf(codes::RESPONSE, &response);
-                stream.write_all(&out_buf).unwrap();
+            if !l.remove_member(user) {
+                outbox.push(Message::Error(ErrorCode::NotInRoom));
+                return false;
             }
+            l.notify_members(&users, room, user, MembershipChange::Left);
+            l.is_abandoned()
         }
         None => {
-            let err_buff: [u8; 2] = two_op_buf(codes::ERROR, codes::error::INVALID_ROOM);
-            stream.write_all(&err_buff).unwrap();
+            outbox.push(Message::Error(ErrorCode::InvalidRoom));
+            return false;
         }
+    };
+    if abandoned {
+        server.drop_if_abandoned(room); //drop the room if this was the last member
     }
+    lock(&leaver.rooms).remove(room);
+    let response: String = format!("Left {}. Current rooms: {}", room, leaver.room_list());
+    outbox.push(Message::Response(response));
+    true
 }
 
-/// Iterate on all rooms, capture each room name which has the user
-/// return a vec of strings of room names
-fn get_rooms_of_user(server: &Arc<Mutex<Server>>, user: &str) -> Vec<String> {
-    let mut result: Vec<String> = vec![];
-    let guard: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-    let rooms: std::collections::hash_map::Keys<'_, String, Vec<String>> = guard.rooms.keys();
-    rooms.for_each(|room: &String| {
-        let user_vec: &Vec<String> = guard.rooms.get(room).unwrap();
-        for usr in user_vec {
-            if usr.eq(user) {
-                result.push(room.to_string());
-                break;
+/// Apply a moderation command from an operator of a room to another user in it.
+/// The user acted on is notified with the same message, naming the operator who acted
+fn moderate(server: &Arc<State>, operator: &str, action: Message, outbox: &Outbox) {
+    let (room_name, target): (String, String) = match &action {
+        Message::Kick { room, nick }
+        | Message::Ban { room, nick }
+        | Message::Unban { room, nick }
+        | Message::Op { room, nick }
+        | Message::Deop { room, nick } => (room.to_string(), nick.to_string()),
+        _ => return,
+    };
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    let mut room: MutexGuard<'_, Room> = match rooms.get(&room_name) {
+        Some(room) => lock(room),
+        None => {
+            outbox.push(Message::Error(ErrorCode::InvalidRoom));
+            return;
+        }
+    };
+    if !room.has_member(operator) {
+        outbox.push(Message::Error(ErrorCode::NotInRoom));
+        return;
+    }
+    if !room.is_operator(operator) {
+        outbox.push(Message::Error(ErrorCode::NotOperator));
+        return;
+    }
+
+    let notice: Message;
+    let response: String;
+    // Whether the target was removed from the room, which its other members are told about
+    let mut removed: bool = false;
+    match action {
+        Message::Kick { .. } => {
+            if !room.kick(&target) {
+                outbox.push(Message::Error(ErrorCode::NoSuchUser));
+                return;
+            }
+            removed = true;
+            notice = Message::Kick {
+                room: room_name.to_string(),
+                nick: operator.to_string(),
+            };
+            response = format!("Kicked {} from {}", target, room_name);
+        }
+        Message::Ban { .. } => {
+            removed = room.ban(&target);
+            notice = Message::Ban {
+                room: room_name.to_string(),
+                nick: operator.to_string(),
+            };
+            response = format!("Banned {} from {}", target, room_name);
+        }
+        Message::Unban { .. } => {
+            if !room.banned.remove(&target) {
+                outbox.push(Message::Error(ErrorCode::NoSuchUser));
+                return;
+            }
+            notice = Message::Unban {
+                room: room_name.to_string(),
+                nick: operator.to_string(),
+            };
+            response = format!("Unbanned {} from {}", target, room_name);
+        }
+        Message::Op { .. } => {
+            if !room.has_member(&target) {
+                outbox.push(Message::Error(ErrorCode::NoSuchUser));
+                return;
+            }
+            room.operators.insert(target.to_string());
+            notice = Message::Op {
+                room: room_name.to_string(),
+                nick: operator.to_string(),
+            };
+            response = format!("{} is now an operator of {}", target, room_name);
+        }
+        Message::Deop { .. } => {
+            if !room.has_member(&target) {
+                outbox.push(Message::Error(ErrorCode::NoSuchUser));
+                return;
             }
+            room.operators.remove(&target);
+            notice = Message::Deop {
+                room: room_name.to_string(),
+                nick: operator.to_string(),
+            };
+            response = format!("{} is no longer an operator of {}", target, room_name);
+        }
+        _ => return,
+    }
+    if removed {
+        room.notify_members(&users, &room_name, &target, MembershipChange::Left);
+    }
+    let persistent: bool = room.persistent;
+    let abandoned: bool = room.is_abandoned();
+    drop(room);
+    drop(rooms);
+    if let Some(target_user) = users.get(&target) {
+        if removed {
+            lock(&target_user.rooms).remove(&room_name);
         }
+        target_user.notify(&notice);
+    }
+    drop(users);
+    if removed {
+        server.emit(Event::Left {
+            nick: target.to_string(),
+            room: room_name.to_string(),
+        });
+    }
+    if abandoned {
+        server.drop_if_abandoned(&room_name); //drop the room if an operator kicked or banned themselves last
+    }
+    if persistent {
+        server.save_rooms();
+    }
+    outbox.push(Message::Response(response));
+}
+
+/// Set or clear the topic of a room, which only its operators may do.
+/// Every member of the room, including the operator, is told the new topic
+fn set_topic(server: &Arc<State>, user: &str, room_name: &str, topic: &str, outbox: &Outbox) {
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    let mut room: MutexGuard<'_, Room> = match rooms.get(room_name) {
+        Some(room) => lock(room),
+        None => {
+            outbox.push(Message::Error(ErrorCode::InvalidRoom));
+            return;
+        }
+    };
+    if !room.has_member(user) {
+        outbox.push(Message::Error(ErrorCode::NotInRoom));
+        return;
+    }
+    if !room.is_operator(user) {
+        outbox.push(Message::Error(ErrorCode::NotOperator));
+        return;
+    }
+    room.topic = topic.to_string();
+    let notice: Message = Message::SetTopic {
+        room: room_name.to_string(),
+        topic: topic.to_string(),
+    };
+    room.send(&users, &notice, None);
+    let persistent: bool = room.persistent;
+    drop(room);
+    drop(rooms);
+    if persistent {
+        server.save_rooms();
+    }
+}
+
+/// Mark a room persistent or not, which only its operators may do.
+/// Every member of the room, including the operator, is told about the change
+fn set_persistent(
+    server: &Arc<State>,
+    user: &str,
+    room_name: &str,
+    persistent: bool,
+    outbox: &Outbox,
+) {
+    let users: RwLockReadGuard<'_, Users> = read(&server.users);
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    let mut room: MutexGuard<'_, Room> = match rooms.get(room_name) {
+        Some(room) => lock(room),
+        None => {
+            outbox.push(Message::Error(ErrorCode::InvalidRoom));
+            return;
+        }
+    };
+    if !room.has_member(user) {
+        outbox.push(Message::Error(ErrorCode::NotInRoom));
+        return;
+    }
+    if !room.is_operator(user) {
+        outbox.push(Message::Error(ErrorCode::NotOperator));
+        return;
+    }
+    room.persistent = persistent;
+    if !persistent {
+        // Operators who are away only kept their status because the room was persistent
+        let Room {
+            members, operators, ..
+        } = &mut *room;
+        operators.retain(|u: &String| members.contains_key(u));
+    }
+    let notice: Message = Message::Persist {
+        room: room_name.to_string(),
+        persistent,
+    };
+    room.send(&users, &notice, None);
+    drop(room);
+    drop(rooms);
+    server.save_rooms();
+}
+
+/// Rename a user everywhere they appear, all under the users' write lock so nobody sees a half renamed user.
+/// The user and everyone sharing a room with them are told about the new nickname.
+/// Returns whether the user was renamed
+fn change_nick(server: &Arc<State>, old: &str, new: &str, outbox: &Outbox) -> bool {
+    if !accounts::valid_nick(new) {
+        outbox.push(Message::Error(ErrorCode::Malformed));
+        return false;
+    }
+    let mut users: RwLockWriteGuard<'_, Users> = write(&server.users);
+    if users.contains_key(new) {
+        outbox.push(Message::Error(ErrorCode::NicknameCollision));
+        return false;
+    }
+    if lock(&server.accounts).is_registered(new) {
+        // There is no password to check, so a registered nickname can only be taken by connecting with it
+        outbox.push(Message::Error(ErrorCode::BadCredentials));
+        return false;
+    }
+    let user: User = match users.remove(old) {
+        Some(user) => user,
+        None => return false,
+    };
+    let joined: Vec<String> = lock(&user.rooms).iter().cloned().collect();
+    users.insert(new.to_string(), user);
+
+    let mut notify: HashSet<String> = HashSet::new();
+    notify.insert(new.to_string());
+    let mut renamed_operator: bool = false;
+    let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&server.rooms);
+    for name in &joined {
+        let mut room: MutexGuard<'_, Room> = match rooms.get(name) {
+            Some(room) => lock(room),
+            None => continue,
+        };
+        if let Some(joins_before) = room.members.remove(old) {
+            room.members.insert(new.to_string(), joins_before);
+        }
+        if room.operators.remove(old) {
+            room.operators.insert(new.to_string());
+            renamed_operator |= room.persistent;
+        }
+        notify.extend(room.members.keys().cloned());
+    }
+    drop(rooms);
+    let notice: Message = Message::ChangeNick {
+        old: old.to_string(),
+        nick: new.to_string(),
+    };
+    for nick in &notify {
+        if let Some(recipient) = users.get(nick) {
+            recipient.notify(&notice);
+        }
+    }
+    if renamed_operator {
+        server.save_rooms();
+    }
+    println!("{} is now known as {}", old, new);
+    true
+}
+
+/// Whether a nickname may be used with the password given for it, if any.
+/// Nicknames without an account need no password
+async fn authenticate(server: &Arc<State>, nickname: &str, password: Option<String>) -> bool {
+    let hash: Option<String> = lock(&server.accounts).hash_of(nickname);
+    match (hash, password) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        // Checked without the lock and away from the tasks serving connections, since verifying is deliberately slow
+        (Some(hash), Some(password)) => {
+            tokio::task::spawn_blocking(move || accounts::verify_password(&password, &hash))
+                .await
+                .unwrap_or(false)
+        }
+    }
+}
+
+/// Register the user's nickname as an account with a password, or change the password of its account.
+/// The password is hashed before taking the lock, since hashing is deliberately slow,
+/// so this is run on a blocking thread rather than by the task serving the connection
+fn register_account(server: &Arc<State>, nickname: &str, password: &str, outbox: &Outbox) {
+    if !accounts::valid_nick(nickname) {
+        outbox.push(Message::Error(ErrorCode::Malformed));
+        return;
+    }
+    let hash: String = match accounts::hash_password(password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("Failed to hash the password for {}: {}", nickname, e);
+            outbox.push(Message::Response(format!(
+                "Could not register {}",
+                nickname
+            )));
+            return;
+        }
+    };
+    let mut accounts: MutexGuard<'_, Accounts> = lock(&server.accounts);
+    let existed: bool = accounts.is_registered(nickname);
+    if let Err(e) = accounts.set(nickname, hash) {
+        eprintln!("Failed to save accounts: {}", e);
+    }
+    drop(accounts);
+    let response: String = if existed {
+        format!("Changed the password for {}", nickname)
+    } else {
+        println!("{} has registered an account", nickname);
+        format!(
+            "Registered {}. Its password is needed to connect with it from now on",
+            nickname
+        )
+    };
+    outbox.push(Message::Response(response));
+}
+
+/// Read one message sent while a connection is being set up, before the user is registered.
+/// A message which cannot be decoded is answered with `Malformed` and ends the connection,
+/// as does not sending one in time, so that silent connections do not hold on to the pending slots
+async fn read_setup_message(
+    server: &State,
+    reader: &mut Reader,
+    writer: &mut Writer,
+) -> crate::Result<Message> {
+    let result: crate::Result<Message> =
+        match tokio::time::timeout(server.setup_timeout, read_message_async(reader)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "did not register in time",
+            ))),
+        };
+    match result {
+        Err(Error::Decode(e)) => {
+            send_async(writer, &Message::Error(ErrorCode::Malformed)).await?;
+            Err(Error::Decode(e))
+        }
+        result => result,
+    }
+}
+
+/// Reply to the version handshake with the server's version, name and capabilities.
+/// Returns whether the client's protocol version is one this server supports
+async fn join_server(writer: &mut Writer, peer: SocketAddr, version: u16) -> crate::Result<bool> {
+    let info: Message = Message::ServerInfo {
+        version: PROTOCOL_VERSION,
+        name: SERVER_NAME.to_string(),
+        capabilities: SERVER_CAPABILITIES
+            .iter()
+            .map(|c: &&str| c.to_string())
+            .collect(),
+    };
+    send_async(writer, &info).await?;
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        Ok(true)
+    } else {
+        println!("{} uses unsupported protocol version {}", peer, version);
+        send_async(writer, &Message::Error(ErrorCode::VersionMismatch)).await?;
+        Ok(false)
+    }
+}
+
+/// Serve one connection until it is closed or fails, then remove its user, if one registered
+async fn handle_connection(server: &Arc<State>, stream: AsyncStream, peer: SocketAddr) {
+    println!("{} has connected", peer);
+    let mut nickname: Option<String> = None;
+    let result: crate::Result<()> = serve_connection(server, stream, peer, &mut nickname).await;
+    if let Some(nick) = &nickname {
+        remove_user(server, nick);
+        server.emit(Event::Disconnected {
+            nick: nick.to_string(),
+        });
+    }
+    server.release(peer.ip(), nickname.is_some());
+    let who: String = match &nickname {
+        Some(nick) => format!("{} with nickname {}", peer, nick),
+        None => peer.to_string(),
+    };
+    match result {
+        Ok(()) | Err(Error::Closed) => println!("{} has closed the connection", who),
+        Err(e) => eprintln!("{} has been disconnected: {}", who, e),
+    }
+}
+
+/// The connection must first complete the version handshake and then register a nickname,
+/// after which every message is handled as a user command.
+/// `nickname` is set once the user is registered, so that the caller can remove them
+async fn serve_connection(
+    server: &Arc<State>,
+    stream: AsyncStream,
+    peer: SocketAddr,
+    nickname: &mut Option<String>,
+) -> crate::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader: Reader = AsyncFrameReader::new(reader);
+    let capabilities: HashSet<String> =
+        match read_setup_message(server, &mut reader, &mut writer).await? {
+            Message::JoinServer {
+                version,
+                capabilities,
+            } => {
+                if !join_server(&mut writer, peer, version).await? {
+                    return Ok(());
+                }
+                capabilities.into_iter().collect()
+            }
+            _ => {
+                send_async(&mut writer, &Message::Error(ErrorCode::NotYetRegistered)).await?;
+                return Ok(());
+            }
+        };
+    let (mut nick, password) = match read_setup_message(server, &mut reader, &mut writer).await? {
+        Message::RegisterNick { nick, password } => (nick, password),
+        _ => {
+            send_async(&mut writer, &Message::Error(ErrorCode::NotYetRegistered)).await?;
+            return Ok(());
+        }
+    };
+    // A name the client library would refuse to send could never be messaged or kicked
+    if !accounts::valid_nick(&nick) {
+        send_async(&mut writer, &Message::Error(ErrorCode::Malformed)).await?;
+        return Ok(());
+    }
+    if !authenticate(server, &nick, password).await {
+        println!("{} failed to authenticate as {}", peer, nick);
+        send_async(&mut writer, &Message::Error(ErrorCode::BadCredentials)).await?;
+        return Ok(());
+    }
+
+    let outbox: Outbox = match register_nick(server, &nick, capabilities, writer, peer).await? {
+        Some(outbox) => outbox,
+        None => return Ok(()),
+    };
+    *nickname = Some(nick.to_string());
+    server.emit(Event::Registered {
+        nick: nick.to_string(),
+        peer,
     });
-    result
-}
-
-/// Entrypoint for the server
-/// Main thread -> Main Menu
-/// We spawn one thread to manage the entire TCP incoming process (seperate from main thread)
-/// Each connected IP gets a spawned thread in the `for` loop
-/// Before looping to handle generic client input, we handle the special case of the nickname registration requirnment
-pub fn start() {
-    let mut host: String = "0.0.0.0:".to_string();
-    host.push_str(&DEFAULT_PORT.to_string());
-    let listener_result: Result<TcpListener, std::io::Error> = TcpListener::bind(host.to_owned());
-    match listener_result {
-        Ok(listener) => {
-            let server: Arc<Mutex<Server>> = Arc::new(Mutex::new(Server::new()));
-            let server_outer: Arc<Mutex<Server>> = Arc::clone(&server);
-            clear();
-            println!("Server listening on {}", host);
-
-            thread::spawn(move || {
-                for tcpstream in listener.incoming() {
-                    match tcpstream {
-                        Ok(mut stream) => {
-                            let mut buf_in: [u8; 1024] = [0; 1024];
-                            let server_inner: Arc<Mutex<Server>> = Arc::clone(&server_outer);
-
-                            thread::spawn(move || {
-                                let nickname: String;
-                                println!("{} has connected", stream.peer_addr().unwrap());
-                                match stream.read(&mut buf_in) {
-                                    Ok(0) => {
-                                        println!(
-                                            "{} has closed the connection",
-                                            stream.peer_addr().unwrap()
-                                        );
-                                    }
-                                    Ok(size) => {
-                                        let cmd_bytes: &[u8] = &buf_in[0..1];
-                                        let param_bytes: &[u8] = &buf_in[1..size];
-                                        if cmd_bytes[0] == codes::REGISTER_NICK {
-                                            nickname =
-                                                String::from_utf8_lossy(param_bytes).to_string();
-                                            register_nick(&server_inner, &nickname, &mut stream);
-                                            loop {
-                                                match stream.read(&mut buf_in) {
-                                                    Ok(0) => {
-                                                        println!("{} with nickname {} has closed the connection", stream.peer_addr().unwrap(), nickname);
-                                                        remove_user(&server_inner, &nickname);
-                                                        break;
-                                                    }
-                                                    Ok(size) => {
-                                                        let cmd_bytes: &[u8] = &buf_in[0..1];
-                                                        let param_bytes: &[u8] = &buf_in[1..size];
-
-                                                        handle_client(
-                                                            &server_inner,
-                                                            &mut stream,
-                                                            &nickname,
-                                                            cmd_bytes,
-                                                            param_bytes,
-                                                        );
-                                                    }
-                                                    Err(_) => {
-                                                        eprintln!("Error parsing client");
-                                                        let out_buf: [u8; 1] =
-                                                            one_op_buf(codes::QUIT);
-                                                        stream.write_all(&out_buf).unwrap();
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                        } else {
-                                            let err_buff: [u8; 2] = two_op_buf(
-                                                codes::ERROR,
-                                                codes::error::NOT_YET_REGISTERED,
-                                            );
-                                            stream.write_all(&err_buff).unwrap();
-                                        }
-                                    }
-                                    Err(_) => {
-                                        eprintln!("Error parsing client");
-                                        stream.write_all(&one_op_buf(codes::QUIT)).unwrap();
-                                    }
-                                }
-                            });
-                        }
-                        Err(_) => {
-                            eprintln!("Error accepting connections!");
-                        }
-                    }
+    let mut closing: watch::Receiver<Closing> = outbox.closing.subscribe();
+    loop {
+        let result: crate::Result<Message> = tokio::select! {
+            result = read_message_async(&mut reader) => result,
+            _ = closing.wait_for(|c: &Closing| *c != Closing::Open) => Err(Error::Closed),
+        };
+        if let Ok(_) | Err(Error::Decode(_)) = result {
+            outbox.seen();
+        }
+        match result {
+            Ok(Message::Quit) => return Ok(()),
+            Ok(Message::ChangeNick { nick: new, .. }) => {
+                if new == nick {
+                    outbox.push(Message::ResponseOk);
+                } else if change_nick(server, &nick, &new, &outbox) {
+                    *nickname = Some(new.to_string());
+                    let old: String = std::mem::replace(&mut nick, new);
+                    server.emit(Event::NickChanged {
+                        old,
+                        new: nick.to_string(),
+                    });
                 }
-            });
+            }
+            Ok(Message::RegisterAccount { password }) => {
+                let (server_inner, outbox_inner, nick_inner): (Arc<State>, Outbox, String) =
+                    (Arc::clone(server), outbox.clone(), nick.to_string());
+                let _ = tokio::task::spawn_blocking(move || {
+                    register_account(&server_inner, &nick_inner, &password, &outbox_inner)
+                })
+                .await;
+            }
+            Ok(message) => handle_client(server, &outbox, &nick, message),
+            Err(Error::Decode(_e)) => {
+                #[cfg(debug_assertions)]
+                println!("Malformed message from {}: {}", nick, _e);
+                outbox.push(Message::Error(ErrorCode::Malformed));
+            }
+            Err(e) => {
+                outbox.push(Message::Quit);
+                return Err(e);
+            }
+        }
+    }
+}
 
-            // Main Menu Loop on the main thread.
-            loop {
-                println!("0: Quit Server");
-                println!("1: list connected users");
-                println!("2: list rooms");
-                println!("3: Broadcast message to all");
-                println!("4: Freeze server via double lock (for testing)");
-                let inp: String = input!("");
-                match inp.parse::<u8>() {
-                    Ok(num) => match num {
-                        0 => {
-                            println!("Stopping Server");
-                            disconnect_all(&server);
-                            break;
-                        }
-                        1 => println!("Users: {:?}", server.lock().unwrap().users),
-                        2 => println!("Rooms: {:?}", server.lock().unwrap().rooms),
-                        3 => {
-                            let inp2 = input!("Enter message: ");
-                            broadcast(codes::MESSAGE, &server, &inp2);
-                        }
-                        4 => {
-                            let _s1: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-                            let _s2: std::sync::MutexGuard<'_, Server> = server.lock().unwrap();
-                        }
-                        _ => println!("Invalid Input"),
-                    },
-                    Err(_) => {
-                        println!("Invalid input");
+/// Spawn a task for every connection accepted by the listener, which is wrapped in TLS if it is configured.
+/// Connections over the server's limits are sent `SERVER_FULL` and closed straight away.
+/// A TLS connection is closed without `SERVER_FULL`, since sending it would mean waiting for a handshake
+async fn accept_connections(listener: TcpListener, server: Arc<State>, tls: Option<TlsAcceptor>) {
+    loop {
+        match listener.accept().await {
+            Ok((mut tcpstream, peer)) => {
+                if !server.admit(peer.ip()) {
+                    println!("{} rejected, the server is full", peer.ip());
+                    if tls.is_none() {
+                        let full: Message = Message::Error(ErrorCode::ServerFull);
+                        let _ = send_async(&mut tcpstream, &full).await;
                     }
+                    let _ = tcpstream.shutdown().await;
+                    continue;
                 }
+                let server_inner: Arc<State> = Arc::clone(&server);
+                let tls_inner: Option<TlsAcceptor> = tls.clone();
+                tokio::spawn(async move {
+                    let stream: AsyncStream = match tls_inner {
+                        Some(acceptor) => match tokio::time::timeout(
+                            server_inner.setup_timeout,
+                            acceptor.accept(tcpstream),
+                        )
+                        .await
+                        {
+                            Ok(Ok(stream)) => Box::new(stream),
+                            Ok(Err(e)) => {
+                                eprintln!("{} could not start TLS: {}", peer, e);
+                                server_inner.release(peer.ip(), false);
+                                return;
+                            }
+                            // Dropping the handshake closes the socket
+                            Err(_) => {
+                                eprintln!("{} did not finish the TLS handshake in time", peer);
+                                server_inner.release(peer.ip(), false);
+                                return;
+                            }
+                        },
+                        None => Box::new(tcpstream),
+                    };
+                    handle_connection(&server_inner, stream, peer).await;
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting connections: {}", e);
+                // Such as running out of file descriptors, which accepting again straight away will not fix
+                tokio::time::sleep(Duration::from_millis(100)).await;
             }
         }
-        Err(_) => {
-            eprintln!("Failed to bind port. Try again");
+    }
+}
+
+/// A chat server to embed in another program, built up from [`Config`] settings and event hooks.
+/// [`Server::spawn`] serves in the background and returns a [`Handle`] to stop it with,
+/// and [`Server::run`] serves on the calling thread until the process is signalled
+pub struct Server {
+    config: Config,
+    /// Addresses given with [`Server::bind`], which replace those in the config
+    binds: Vec<String>,
+    hooks: Vec<Hook>,
+}
+
+/// A running server, returned by [`Server::spawn`]. Dropping it shuts the server down
+pub struct Handle {
+    state: Arc<State>,
+    local_addrs: Vec<SocketAddr>,
+    stop: watch::Sender<bool>,
+    /// The thread running the server, until it has been waited for
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+impl Server {
+    /// A server with the default settings, listening on every interface on the default port
+    pub fn new() -> Self {
+        Server::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Server {
+            config,
+            binds: vec![],
+            hooks: vec![],
         }
     }
+
+    /// Listen on a socket address, such as `127.0.0.1:0` for any free port of the loopback interface.
+    /// May be called more than once, and replaces the addresses in the config
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.binds.push(addr.to_string());
+        self
+    }
+
+    /// Most users which may be registered at once
+    pub fn max_users(mut self, max: usize) -> Self {
+        self.config.max_users = Some(max);
+        self
+    }
+
+    /// Most connections which may be open without having registered a nickname yet
+    pub fn max_pending(mut self, max: usize) -> Self {
+        self.config.max_pending = Some(max);
+        self
+    }
+
+    /// Most connections which may be open from one IP address
+    pub fn max_per_ip(mut self, max: usize) -> Self {
+        self.config.max_per_ip = Some(max);
+        self
+    }
+
+    /// How many messages may wait to be written to one client, and what happens to a client whose queue is full
+    pub fn queue(mut self, len: usize, overflow: OverflowPolicy) -> Self {
+        self.config.queue_len = len;
+        self.config.overflow = overflow;
+        self
+    }
+
+    /// Probe clients silent for `keepalive` with `KEEP_ALIVE`, and disconnect clients silent for `timeout`
+    pub fn idle(mut self, keepalive: Duration, timeout: Duration) -> Self {
+        self.config.keepalive_interval = keepalive;
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// How many recent messages each room keeps, and how many of them are sent to a user who joins it
+    pub fn history(mut self, len: usize, on_join: usize) -> Self {
+        self.config.history_len = len;
+        self.config.history_on_join = on_join;
+        self
+    }
+
+    /// Save persistent rooms and accounts in a directory, and load them from it on start
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Serve TLS with the certificate chain and private key in these PEM files
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.config.tls_cert = Some(cert.into());
+        self.config.tls_key = Some(key.into());
+        self
+    }
+
+    /// Call `hook` with every [`Event`], from the server's own threads.
+    /// No locks are held while it runs, but it delays the connection which caused the event, so should be quick
+    pub fn on_event<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Load the data directory, listen on every address and start serving on a new thread.
+    /// Fails without serving anything if an address cannot be bound, or the TLS files or data directory cannot be loaded
+    pub fn spawn(self) -> io::Result<Handle> {
+        let Server {
+            mut config,
+            binds,
+            hooks,
+        } = self;
+        if !binds.is_empty() {
+            config.listen = binds;
+        }
+        let tls: Option<TlsAcceptor> = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let tls_config: Arc<rustls::ServerConfig> =
+                    tls::server_config(cert, key).map_err(|e: io::Error| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("failed to load the TLS certificate and key: {}", e),
+                        )
+                    })?;
+                Some(TlsAcceptor::from(tls_config))
+            }
+            _ => None,
+        };
+
+        let mut state: State = State::new(&config);
+        state.hooks = hooks;
+        if let Some(dir) = &config.data_dir {
+            fs::create_dir_all(dir).map_err(|e: io::Error| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to create data directory {}: {}", dir.display(), e),
+                )
+            })?;
+            let accounts: Accounts = Accounts::load(dir).map_err(|e: String| {
+                io::Error::other(format!("failed to load accounts from {}", e))
+            })?;
+            state.accounts = Mutex::new(accounts);
+            // Starting without them would overwrite the file on the next save
+            let loaded: Option<HashMap<String, Room>> = load_rooms(dir, config.history_len)
+                .map_err(|e: String| {
+                    io::Error::other(format!("failed to load rooms from {}", e))
+                })?;
+            if let Some(rooms) = loaded {
+                let rooms: HashMap<String, Mutex<Room>> = rooms
+                    .into_iter()
+                    .map(|(name, room): (String, Room)| (name, Mutex::new(room)))
+                    .collect();
+                state.rooms = RwLock::new(rooms);
+            }
+        }
+
+        let mut listeners: Vec<std::net::TcpListener> = vec![];
+        for addr in &config.listen {
+            let listener: std::net::TcpListener =
+                std::net::TcpListener::bind(addr).map_err(|e: io::Error| {
+                    io::Error::new(e.kind(), format!("failed to bind {}: {}", addr, e))
+                })?;
+            listener.set_nonblocking(true)?;
+            listeners.push(listener);
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to listen on",
+            ));
+        }
+        let local_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(std::net::TcpListener::local_addr)
+            .collect::<io::Result<Vec<SocketAddr>>>()?;
+        for addr in &local_addrs {
+            println!("Server listening on {}", addr);
+        }
+        if let Some(cert) = &config.tls_cert {
+            // Clients can pin this, such as when the certificate is self-signed
+            if let Ok(fingerprint) = tls::cert_fingerprint(cert) {
+                println!(
+                    "TLS certificate SHA-256 fingerprint: {}",
+                    tls::format_fingerprint(&fingerprint)
+                );
+            }
+        }
+
+        let runtime: tokio::runtime::Runtime = tokio::runtime::Runtime::new()?;
+        let state: Arc<State> = Arc::new(state);
+        let (stop, mut stopping) = watch::channel::<bool>(false);
+        let state_inner: Arc<State> = Arc::clone(&state);
+        let (keepalive, timeout) = (config.keepalive_interval, config.idle_timeout);
+        let thread: thread::JoinHandle<()> = thread::spawn(move || {
+            runtime.block_on(async move {
+                for listener in listeners {
+                    match TcpListener::from_std(listener) {
+                        Ok(listener) => {
+                            let state: Arc<State> = Arc::clone(&state_inner);
+                            tokio::spawn(accept_connections(listener, state, tls.clone()));
+                        }
+                        Err(e) => eprintln!("Failed to listen: {}", e),
+                    }
+                }
+                let state: Arc<State> = Arc::clone(&state_inner);
+                tokio::spawn(reap_idle_users(state, keepalive, timeout));
+                if config.data_dir.is_some() {
+                    tokio::spawn(persist_rooms(Arc::clone(&state_inner)));
+                }
+                let _ = stopping.wait_for(|stop: &bool| *stop).await;
+                shutdown(&state_inner).await;
+            });
+        });
+        Ok(Handle {
+            state,
+            local_addrs,
+            stop,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Serve on the calling thread until the process receives SIGTERM or SIGINT, then disconnect every client and return.
+    /// This installs the process's signal handler, so fails if one has already been installed
+    pub fn run(self) -> io::Result<()> {
+        let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+        ctrlc::set_handler(move || {
+            let _ = stop_tx.send(());
+        })
+        .map_err(io::Error::other)?;
+        let handle: Handle = self.spawn()?;
+        println!("Send SIGTERM or SIGINT to stop");
+        let _ = stop_rx.recv();
+        handle.shutdown();
+        Ok(())
+    }
+}
+
+impl Handle {
+    /// The addresses the server is listening on, with the port chosen for any address bound to port 0
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The nicknames of the registered users, sorted
+    pub fn users(&self) -> Vec<String> {
+        let mut users: Vec<String> = read(&self.state.users).keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// The names of the rooms, sorted
+    pub fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = read(&self.state.rooms).keys().cloned().collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// The members of a room in the order they joined, or None if there is no such room
+    pub fn members(&self, room: &str) -> Option<Vec<String>> {
+        let rooms: RwLockReadGuard<'_, HashMap<String, Mutex<Room>>> = read(&self.state.rooms);
+        let room: MutexGuard<'_, Room> = lock(rooms.get(room)?);
+        Some(
+            room.members_in_order()
+                .into_iter()
+                .map(|member: &String| member.to_string())
+                .collect(),
+        )
+    }
+
+    /// Send a message from the server to every registered user
+    pub fn broadcast(&self, text: &str) {
+        broadcast(
+            &self.state,
+            &Message::Message {
+                text: text.to_string(),
+            },
+        );
+    }
+
+    /// Disconnect every client, save the persistent rooms, and wait for the server to stop.
+    /// Must not be called from an event hook, which runs on the server's own threads
+    pub fn shutdown(&self) {
+        self.stop.send_replace(true);
+        self.wait();
+    }
+
+    /// Wait for the server to stop, after [`Handle::shutdown`] has been called from another thread
+    pub fn wait(&self) {
+        let mut thread: MutexGuard<'_, Option<thread::JoinHandle<()>>> = lock(&self.thread);
+        if let Some(thread) = thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
 }
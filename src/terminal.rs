@@ -0,0 +1,547 @@
+//! The interactive client, which reads commands from stdin and prints what the server sends,
+//! built on [`rust_irc::client::Client`].
+
+#[cfg(unix)]
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
+use prompted::input;
+use rust_irc::client::{self, Client};
+use rust_irc::protocol::{ErrorCode, MembershipChange, Message};
+use rust_irc::tls;
+use rust_irc::{clear, host_with_port, read_line, Error, DEFAULT_PORT, PROTOCOL_VERSION};
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_HOST: &str = "localhost";
+/// How many messages /history asks for when no count is given
+const DEFAULT_HISTORY_COUNT: u32 = 20;
+
+fn read_messages(client: &Client) {
+    for result in client.incoming() {
+        match result {
+            Ok(message) => process_message(message, client),
+            Err(Error::Decode(e)) => eprintln!("Malformed message recieved: {}", e),
+            Err(Error::Closed) => {
+                println!("Server closed the connection. Shutting down client");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!(
+                    "Lost the connection to the server: {}. Shutting down client",
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn process_message(message: Message, client: &Client) {
+    match message {
+        Message::Error(code) => match code {
+            ErrorCode::InvalidRoom => {
+                eprintln!("Operation Performed on an invalid room. Try again");
+            }
+            ErrorCode::NicknameCollision => {
+                eprintln!("Nickname already in use on server. Try /nick with a different one");
+            }
+            ErrorCode::BadCredentials => {
+                eprintln!(
+                    "That nickname is registered. Connect with it and --ask-password to use it"
+                );
+            }
+            ErrorCode::NotInRoom => {
+                eprintln!("Cannot interact with a room you have not joined. Use /join [room].")
+            }
+            ErrorCode::EmptyRoom => {
+                eprintln!("Room is Empty");
+            }
+            ErrorCode::AlreadyInRoom => {
+                eprintln!("You are already in that room");
+            }
+            ErrorCode::NoSuchUser => {
+                eprintln!("No user with that nickname is connected. Use /users to see who is");
+            }
+            ErrorCode::NotOperator => {
+                eprintln!("Only operators of that room may do that");
+            }
+            ErrorCode::Banned => {
+                eprintln!("You are banned from that room");
+            }
+            _ => {
+                eprintln!("Error code: {:x?}", code.to_byte());
+            }
+        },
+
+        Message::Message { text } => {
+            println!("[server]:{}", text);
+        }
+        Message::MessageRoom { room, sender, text } => {
+            if sender != client.nick() {
+                println!("[{}]:[{}]: {}", room, sender, text);
+            }
+        }
+
+        Message::PrivateMessage { nick, text } => {
+            println!("[pm]:[{}]: {}", nick, text);
+        }
+        Message::Kick { room, nick } => {
+            println!("You were kicked from {} by {}", room, nick);
+        }
+        Message::Ban { room, nick } => {
+            println!("You were banned from {} by {}", room, nick);
+        }
+        Message::Unban { room, nick } => {
+            println!("You were unbanned from {} by {}", room, nick);
+        }
+        Message::Op { room, nick } => {
+            println!("{} made you an operator of {}", nick, room);
+        }
+        Message::Deop { room, nick } => {
+            println!("{} removed your operator status in {}", nick, room);
+        }
+        Message::SetTopic { room, topic } => {
+            if topic.is_empty() {
+                println!("* The topic for {} was cleared", room);
+            } else {
+                println!("* The topic for {} is now: {}", room, topic);
+            }
+        }
+        Message::Persist { room, persistent } => {
+            if persistent {
+                println!("* {} is now persistent", room);
+            } else {
+                println!("* {} is no longer persistent", room);
+            }
+        }
+        Message::ChangeNick { old, nick: new } => {
+            // The client has already taken on the new nickname if it was ours
+            if client.nick() == new {
+                println!("* You are now known as {}", new);
+            } else {
+                println!("* {} is now known as {}", old, new);
+            }
+        }
+        Message::Membership { room, nick, change } => match change {
+            MembershipChange::Joined => println!("* {} has joined {}", nick, room),
+            MembershipChange::Left => println!("* {} has left {}", nick, room),
+            MembershipChange::Quit => println!("* {} has quit ({})", nick, room),
+            MembershipChange::TimedOut => println!("* {} has timed out ({})", nick, room),
+        },
+        Message::History {
+            room,
+            time,
+            sender,
+            text,
+        } => {
+            let secs: u64 = time % (24 * 60 * 60);
+            println!(
+                "[{}]:[{}] {:02}:{:02} UTC: {}",
+                room,
+                sender,
+                secs / 3600,
+                secs / 60 % 60,
+                text
+            );
+        }
+        Message::ResponseOk => {}
+        Message::Response(text) => {
+            println!("{}", text);
+        }
+        Message::Quit => {
+            println!("Server has closed the connection. Stopping client");
+            std::process::exit(0);
+        }
+        _ => {
+            eprintln!("BAD RESPONSE = {:x?} ", message.opcode());
+        }
+    }
+}
+
+/// Send a message to the server, stopping the client if the connection has been lost
+fn send_to_server(client: &Client, message: &Message) {
+    check_sent(client.send(message));
+}
+
+/// Report a message the client refused to send, stopping the client if the connection has been lost
+fn check_sent(result: rust_irc::Result<()>) {
+    match result {
+        Ok(()) => {}
+        Err(Error::Invalid(reason)) => eprintln!("Not sent, {}", reason),
+        Err(e) => {
+            eprintln!(
+                "Lost the connection to the server: {}. Shutting down client",
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prompt for a password, without echoing what is typed when stdin is a terminal
+fn read_password(prompt: &str) -> String {
+    print!("{}", prompt);
+    let _ = std::io::stdout().flush();
+    #[cfg(unix)]
+    let saved: Option<Termios> = hide_input();
+    let password: String = read_line().unwrap_or_default();
+    #[cfg(unix)]
+    if let Some(saved) = saved {
+        let _ = tcsetattr(std::io::stdin(), SetArg::TCSANOW, &saved);
+        // The newline typed after the password was not echoed either
+        println!();
+    }
+    password
+}
+
+/// Stop the terminal echoing input, returning its settings to restore afterwards.
+/// None when stdin is not a terminal, such as when the password is piped in
+#[cfg(unix)]
+fn hide_input() -> Option<Termios> {
+    let saved: Termios = tcgetattr(std::io::stdin()).ok()?;
+    let mut hidden: Termios = saved.clone();
+    hidden.local_flags.remove(LocalFlags::ECHO);
+    tcsetattr(std::io::stdin(), SetArg::TCSANOW, &hidden).ok()?;
+    Some(saved)
+}
+
+/// Why the server refused to register us, and what to do about it
+fn refusal(code: ErrorCode) -> String {
+    match code {
+        ErrorCode::NicknameCollision => {
+            "Nickname already in use on server. Connect again with a different one".to_string()
+        }
+        ErrorCode::BadCredentials => {
+            "Wrong or missing password for that nickname. Connect again with --ask-password"
+                .to_string()
+        }
+        ErrorCode::ServerFull => "Server is full. Try again later".to_string(),
+        ErrorCode::VersionMismatch => format!(
+            "Server does not support protocol version {}. Update the client and try again",
+            PROTOCOL_VERSION
+        ),
+        other => format!("Server refused the connection: {}", other),
+    }
+}
+
+/// Build the room moderation message for one of the operator commands
+fn moderation_message(cmd: &str, room: &str, target: &str) -> Message {
+    let room: String = room.to_string();
+    let nick: String = target.to_string();
+    match cmd {
+        "/kick" => Message::Kick { room, nick },
+        "/ban" => Message::Ban { room, nick },
+        "/unban" => Message::Unban { room, nick },
+        "/op" => Message::Op { room, nick },
+        _ => Message::Deop { room, nick },
+    }
+}
+
+fn help() {
+    println!("Available commands:");
+    println!("/quit <- Disconnect and stop the client");
+    println!("/rooms <- List all of the rooms on the server");
+    println!("/users <- List all of the user connected to the server");
+    println!("/list [room-name] <- List all of the users in the given room");
+    println!("/join [room-name] <- Join the given room. Create the room if it does not exist");
+    println!(
+        "/leave [room-name] <- Leave the given room. Error if you are not already in the room"
+    );
+    println!("/msg [room-name] [message] <- Send a message to just the given room");
+    println!("/nick [nickname] <- Change your nickname, keeping your rooms");
+    println!(
+        "/register [password] <- Register your nickname, so only you may use it, or change its password"
+    );
+    println!(
+        "/history [room-name] [count] <- Show recent messages in the room, {} unless a count is given",
+        DEFAULT_HISTORY_COUNT
+    );
+    println!("/pm [nickname] [message] <- Send a private message to the given user. Also /query");
+    println!("/topic [room-name] <- Show the topic of the given room");
+    println!(
+        "Room operators (the first user to join a room, or users made operator) may also use:"
+    );
+    println!("/topic [room-name] [topic] <- Set the topic of the room");
+    println!("/kick [room-name] [nickname] <- Remove the user from the room");
+    println!(
+        "/ban [room-name] [nickname] <- Remove the user from the room and stop them rejoining"
+    );
+    println!("/unban [room-name] [nickname] <- Allow a banned user to join the room again");
+    println!("/op [room-name] [nickname] <- Make the user an operator of the room");
+    println!("/deop [room-name] [nickname] <- Remove the user's operator status in the room");
+    println!(
+        "/persist [room-name] [on|off] <- Keep the room, its topic, bans and operators when it is empty"
+    );
+}
+
+/// Settings for the client, normally taken from the command line.
+/// The user is prompted for a nickname or host which is not given
+#[derive(Default)]
+pub struct Options {
+    pub nick: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Password for a nickname registered as an account
+    pub password: Option<String>,
+    /// Prompt for the password when it is not set
+    pub ask_password: bool,
+    /// Connect over TLS, verifying the server this way. Plain TCP when None
+    pub tls: Option<tls::Verify>,
+    /// Rooms to join as soon as the nickname is registered
+    pub join: Vec<String>,
+}
+
+/// Client application: take the nickname and host from the options, or prompt the user for them
+/// Try to open a connection to the given host and register the nickname first
+/// After registring the nickname, main loop to take in, parse, and send user commands
+/// On new threads, print what the server sends, and run a watchdog for a possibly unresponsive server
+pub fn start(options: Options) {
+    if options.nick.is_none() || options.host.is_none() {
+        clear();
+        println!("Starting the IRC client. No spaces allowed in nicknames or room names.");
+    }
+    let mut nick: String;
+    match options.nick {
+        Some(n) => nick = n,
+        None => loop {
+            nick = input!("Enter your nickname : ");
+            if nick.contains(' ') {
+                println!("May not contain spaces . Try again");
+            } else if nick.is_empty() {
+                println!("May not be empty . Try again");
+            } else {
+                break;
+            }
+        },
+    }
+
+    let mut host: String = match options.host {
+        Some(h) => h,
+        None => input!("Enter the server host (empty for {}): ", DEFAULT_HOST),
+    };
+    if host.is_empty() {
+        host = DEFAULT_HOST.to_owned();
+    }
+    let host: String = host_with_port(&host, options.port.unwrap_or(DEFAULT_PORT));
+    let password: Option<String> = match options.password {
+        None if options.ask_password => {
+            Some(read_password(&format!("Enter the password for {}: ", nick)))
+        }
+        password => password,
+    };
+    let connect_options: client::Options = client::Options {
+        password,
+        tls: options.tls,
+        timeout: None,
+    };
+    let client: Arc<Client> = match Client::connect(&host, &nick, &connect_options) {
+        Ok(client) => Arc::new(client),
+        Err(Error::Refused(code)) => {
+            eprintln!("{}", refusal(code));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to connect to {} with nickname {}: {}",
+                host, nick, e
+            );
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Connected to {} at {} (protocol version {}).\n/help to see available commands",
+        client.server().name,
+        host,
+        client.server().version
+    );
+
+    let reader_client: Arc<Client> = Arc::clone(&client);
+    thread::spawn(move || read_messages(&reader_client));
+
+    //watchdog to send keep_alive and stop client if server fails to respond
+    let watchdog_client: Arc<Client> = Arc::clone(&client);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(5));
+        let silent: Duration = Instant::now().duration_since(watchdog_client.last_heard());
+        if silent > Duration::from_secs(30) {
+            eprintln!("Server is unresponsive. Stopping client");
+            std::process::exit(1);
+        } else if silent > Duration::from_secs(5) {
+            send_to_server(&watchdog_client, &Message::KeepAlive);
+        }
+    });
+
+    for room in options.join {
+        check_sent(client.join(&room));
+    }
+    loop {
+        let inp: String = match read_line() {
+            Some(inp) => inp,
+            None => {
+                //stdin was closed, such as at the end of a script
+                let _ = client.quit();
+                break;
+            }
+        };
+
+        match inp.split_once(' ') {
+            Some((cmd, param)) => match cmd {
+                "/list" => match param.split_once(' ') {
+                    Some((_, _)) => {
+                        eprintln!("Malformaed. Try /list [room-name]");
+                    }
+                    _ => {
+                        let out_msg: Message = Message::ListUsersInRoom {
+                            room: param.to_string(),
+                        };
+                        send_to_server(&client, &out_msg);
+                    }
+                },
+                "/join" => match param.split_once(' ') {
+                    Some((_, _)) => {
+                        eprintln!("Malformed. Try /join [room-name]");
+                    }
+                    _ => {
+                        check_sent(client.join(param));
+                    }
+                },
+
+                "/leave" => match param.split_once(' ') {
+                    Some((_, _)) => {
+                        eprintln!("Malformed. Try /leave [room-name]");
+                    }
+                    _ => {
+                        check_sent(client.leave(param));
+                    }
+                },
+                "/kick" | "/ban" | "/unban" | "/op" | "/deop" => match param.split_once(' ') {
+                    Some((room, target)) if !target.contains(' ') => {
+                        let out_msg: Message = moderation_message(cmd, room, target);
+                        send_to_server(&client, &out_msg);
+                    }
+                    _ => {
+                        eprintln!("Usage: {} [room-name] [nickname]", cmd);
+                    }
+                },
+                "/topic" => {
+                    let out_msg: Message = match param.split_once(' ') {
+                        Some((room, topic)) => Message::SetTopic {
+                            room: room.to_string(),
+                            topic: topic.to_string(),
+                        },
+                        None => Message::GetTopic {
+                            room: param.to_string(),
+                        },
+                    };
+                    send_to_server(&client, &out_msg);
+                }
+                "/register" if !param.is_empty() => {
+                    let out_msg: Message = Message::RegisterAccount {
+                        password: param.to_string(),
+                    };
+                    send_to_server(&client, &out_msg);
+                }
+                "/persist" => match param.split_once(' ') {
+                    Some((room, setting @ ("on" | "off"))) => {
+                        let out_msg: Message = Message::Persist {
+                            room: room.to_string(),
+                            persistent: setting == "on",
+                        };
+                        send_to_server(&client, &out_msg);
+                    }
+                    _ => {
+                        eprintln!("Usage: /persist [room-name] [on|off]");
+                    }
+                },
+                "/nick" => match param.split_once(' ') {
+                    Some((_, _)) => {
+                        eprintln!("Nicknames may not contain spaces. Try /nick [nickname]");
+                    }
+                    _ if param.is_empty() => {
+                        eprintln!("Usage: /nick [nickname]");
+                    }
+                    _ => {
+                        check_sent(client.change_nick(param));
+                    }
+                },
+                "/history" => {
+                    let (room, count) = match param.split_once(' ') {
+                        Some((room, count)) => (room, count.parse::<u32>().ok()),
+                        None => (param, Some(DEFAULT_HISTORY_COUNT)),
+                    };
+                    match count {
+                        Some(count) if !room.is_empty() => {
+                            let out_msg: Message = Message::GetHistory {
+                                room: room.to_string(),
+                                count,
+                            };
+                            send_to_server(&client, &out_msg);
+                        }
+                        _ => {
+                            eprintln!("Usage: /history [room-name] [count]");
+                        }
+                    }
+                }
+                "/pm" | "/query" => match param.split_once(' ') {
+                    Some((recipient, msg)) => {
+                        check_sent(client.private_message(recipient, msg));
+                    }
+                    _ => {
+                        eprintln!("Usage: /pm [nickname] [message]");
+                    }
+                },
+                "/msg" => match param.split_once(' ') {
+                    Some((room, msg)) => {
+                        check_sent(client.message_room(room, msg));
+                    }
+                    _ => {
+                        eprintln!("Usage: /msg [room] [message]");
+                    }
+                },
+                _ => {
+                    let out_msg: Message = Message::Message { text: inp };
+                    send_to_server(&client, &out_msg);
+                }
+            },
+
+            _ => match inp.as_str() {
+                "/quit" => {
+                    let _ = client.quit();
+                    break;
+                }
+                "/rooms" => {
+                    send_to_server(&client, &Message::ListRooms);
+                }
+                "/users" => {
+                    send_to_server(&client, &Message::ListUsers);
+                }
+                "/help" => {
+                    help();
+                }
+                "/topic" => {
+                    eprintln!("Usage: /topic [room-name] [topic]");
+                }
+                "/nick" => {
+                    eprintln!("Usage: /nick [nickname]");
+                }
+                "/history" => {
+                    eprintln!("Usage: /history [room-name] [count]");
+                }
+                "/persist" => {
+                    eprintln!("Usage: /persist [room-name] [on|off]");
+                }
+                "/register" => {
+                    eprintln!("Usage: /register [password]");
+                }
+                "/" => {
+                    eprintln!("Invalid command");
+                }
+                _ => {
+                    let out_msg: Message = Message::Message { text: inp };
+                    send_to_server(&client, &out_msg);
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! TLS for connections between the client and server, using rustls.
+//!
+//! A [`TlsStream`] can be cloned like a `TcpStream`, so that one thread reads messages while
+//! another writes them. The clones share one TLS session behind a mutex, which is only held while
+//! records are encrypted, decrypted or written, never while waiting for the peer to send anything.
+//! The server serves TLS with tokio-rustls instead, set up from the same [`server_config`].
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+
+/// How a client decides whether to trust the server's certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verify {
+    /// Trust certificates issued for the server's host name by the Mozilla root certificate authorities
+    PublicRoots,
+    /// Trust certificates issued for the server's host name by the certificate authorities in a PEM file
+    CaFile(PathBuf),
+    /// Trust only the certificate with this SHA-256 fingerprint, whatever its name or issuer,
+    /// such as a self-signed certificate
+    Fingerprint([u8; 32]),
+}
+
+/// Load the server's certificate chain and private key from PEM files
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs: Vec<CertificateDer<'static>> = load_certs(cert)?;
+    let key: PrivateKeyDer<'static> =
+        PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    let config: ServerConfig = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e: rustls::Error| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(Arc::new(config))
+}
+
+/// Settings for a client which verifies the server as given
+pub fn client_config(verify: &Verify) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder();
+    let config: ClientConfig = match verify {
+        Verify::PublicRoots => {
+            let roots: RootCertStore = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Verify::CaFile(path) => {
+            let mut roots: RootCertStore = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e: rustls::Error| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Verify::Fingerprint(fingerprint) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                fingerprint: *fingerprint,
+                provider: Arc::new(rustls::crypto::ring::default_provider()),
+            }))
+            .with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// The SHA-256 fingerprint of the first certificate in a PEM file, as printed by [`format_fingerprint`]
+pub fn cert_fingerprint(cert: &Path) -> io::Result<[u8; 32]> {
+    match load_certs(cert)?.first() {
+        Some(cert) => Ok(fingerprint(cert)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", cert.display()),
+        )),
+    }
+}
+
+/// Colon separated upper case hex, the same as `openssl x509 -fingerprint -sha256` prints
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    fingerprint
+        .iter()
+        .map(|b: &u8| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Read a SHA-256 fingerprint written as hex, with or without colons between bytes
+pub fn parse_fingerprint(text: &str) -> Option<[u8; 32]> {
+    let hex: String = text.chars().filter(|c: &char| *c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut fingerprint: [u8; 32] = [0; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+/// The host of an address such as `chat.local:6667` or `[::1]:6667`, which the server's certificate must name
+pub fn host_name(addr: &str) -> &str {
+    let host: &str = match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    let mut fingerprint: [u8; 32] = [0; 32];
+    fingerprint.copy_from_slice(digest(&SHA256, cert.as_ref()).as_ref());
+    fingerprint
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<CertificateDer<'static>>, _>>())
+        .map_err(|e| pem_error(path, e))
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {:?}", path.display(), e),
+    )
+}
+
+/// Accepts exactly one certificate, while still checking the server holds its private key
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// A TLS session over a TCP connection
+pub struct TlsStream {
+    socket: TcpStream,
+    session: Arc<Mutex<Connection>>,
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+impl TlsStream {
+    /// Start the client side of a session with the server at `host`, and complete the handshake,
+    /// so that a server which cannot be verified is reported straight away
+    pub fn connect(socket: TcpStream, host: &str, config: Arc<ClientConfig>) -> io::Result<Self> {
+        let name: ServerName<'static> = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let session: ClientConnection = ClientConnection::new(config, name)
+            .map_err(|e: rustls::Error| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream: TlsStream = TlsStream {
+            socket,
+            session: Arc::new(Mutex::new(session.into())),
+        };
+        while stream.lock().is_handshaking() {
+            stream.flush_records()?;
+            if stream.lock().wants_read() && !stream.receive()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during the TLS handshake",
+                ));
+            }
+        }
+        stream.flush_records()?;
+        Ok(stream)
+    }
+
+    /// Another handle to the same session, for use on another thread
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream {
+            socket: self.socket.try_clone()?,
+            session: Arc::clone(&self.session),
+        })
+    }
+
+    /// Shut down the connection. Shutting down writes first tells the peer the session is over
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            self.lock().send_close_notify();
+            let _ = self.flush_records();
+        }
+        self.socket.shutdown(how)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.session.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write out every record the session has ready, such as handshake messages and encrypted data
+    fn flush_records(&self) -> io::Result<()> {
+        let mut session: MutexGuard<'_, Connection> = self.lock();
+        while session.wants_write() {
+            session.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for data from the peer, without holding the lock, and pass it to the session.
+    /// Returns false once the peer has closed the connection
+    fn receive(&self) -> io::Result<bool> {
+        let mut chunk: [u8; 4096] = [0; 4096];
+        let size: usize = (&self.socket).read(&mut chunk)?;
+        let mut data: &[u8] = &chunk[..size];
+        let mut session: MutexGuard<'_, Connection> = self.lock();
+        loop {
+            session.read_tls(&mut data)?;
+            if let Err(e) = session.process_new_packets() {
+                // Tell the peer what went wrong before giving up
+                drop(session);
+                let _ = self.flush_records();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            if data.is_empty() {
+                break;
+            }
+        }
+        drop(session);
+        self.flush_records()?;
+        Ok(size > 0)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.lock().reader().read(buf) {
+                Ok(size) => return Ok(size),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // The peer closed the connection without ending the session first. Messages are framed,
+                // so a message cut short is still noticed, and the connection is treated as closed
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            self.receive()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size: usize = self.lock().writer().write(buf)?;
+        self.flush_records()?;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().writer().flush()?;
+        self.flush_records()
+    }
+}
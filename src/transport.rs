@@ -0,0 +1,232 @@
+//! The connection between a client and the server, which may be plain TCP, TLS or an in-memory pipe.
+//!
+//! Code which reads and writes messages works on a [`Stream`] and does not need to know which.
+//! [`duplex`] connects two streams in memory, so protocol flows can be tested without opening sockets.
+//!
+//! The server serves every connection from a few threads, so it uses the asynchronous [`AsyncStream`] instead.
+//! Tokio's TCP streams, TLS streams and in-memory `tokio::io::duplex` pipes are all [`AsyncTransport`]s.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::tls::TlsStream;
+
+/// A connection which can be read and written from several threads through its clones,
+/// with the same meaning for each method as on [`TcpStream`]
+pub trait Transport: Read + Write + Send + Sync + fmt::Debug {
+    /// Another handle to the same connection, for use on another thread
+    fn try_clone(&self) -> io::Result<Stream>;
+
+    /// Shut down reading, writing or both for every handle to the connection
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// How long a read may block before failing. None to wait forever
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// Any connection, as used by the client
+pub type Stream = Box<dyn Transport>;
+
+/// A connection which can be read and written asynchronously, split into halves with `tokio::io::split`
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug> AsyncTransport for T {}
+
+/// Any asynchronous connection, as used by the server
+pub type AsyncStream = Box<dyn AsyncTransport>;
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Transport for TlsStream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        Ok(Box::new(TlsStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TlsStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TlsStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TlsStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Bytes written to one end of a [`duplex`] and not yet read from the other
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    /// Set once either end shuts the pipe down. Reads drain what is left, then see end of file
+    closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+/// One end of a [`duplex`], shared by all of its clones. Dropping the last clone closes the connection
+#[derive(Debug)]
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    /// How long a read waits for the other end to write. None to wait forever
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// One end of a connection held in memory, made by [`duplex`].
+/// Writes never block, and reads block until the other end writes or the connection closes
+#[derive(Debug, Clone)]
+pub struct MemoryStream {
+    end: Arc<End>,
+}
+
+/// Two connected in-memory streams. What is written to one is read from the other.
+/// Both report their peer's address as `127.0.0.1:0`
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let forward: Arc<Pipe> = Arc::new(Pipe::default());
+    let backward: Arc<Pipe> = Arc::new(Pipe::default());
+    let first: MemoryStream = MemoryStream {
+        end: Arc::new(End {
+            incoming: Arc::clone(&backward),
+            outgoing: Arc::clone(&forward),
+            read_timeout: Mutex::new(None),
+        }),
+    };
+    let second: MemoryStream = MemoryStream {
+        end: Arc::new(End {
+            incoming: forward,
+            outgoing: backward,
+            read_timeout: Mutex::new(None),
+        }),
+    };
+    (first, second)
+}
+
+impl Transport for MemoryStream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.end.incoming.close();
+        }
+        if how != Shutdown::Read {
+            self.end.outgoing.close();
+        }
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self
+            .end
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = timeout;
+        Ok(())
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout: Option<Duration> = *self
+            .end
+            .read_timeout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let deadline: Option<Instant> = timeout.map(|timeout: Duration| Instant::now() + timeout);
+        let pipe: &Pipe = &self.end.incoming;
+        let mut state: MutexGuard<'_, PipeState> = pipe.lock();
+        while state.buffer.is_empty() && !state.closed {
+            state = match deadline {
+                None => pipe
+                    .readable
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let left: Duration = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    pipe.readable
+                        .wait_timeout(state, left)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+        let count: usize = buf.len().min(state.buffer.len());
+        for (slot, byte) in buf.iter_mut().zip(state.buffer.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pipe: &Pipe = &self.end.outgoing;
+        let mut state: MutexGuard<'_, PipeState> = pipe.lock();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buffer.extend(buf);
+        pipe.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use rust_irc::{
+    bot::{Bot, Context, Regex},
+    client::{Client, Options},
+    framing::FrameReader,
+    protocol::{read_message, send, Message},
+    server::{Event, Handle, Server},
+    PROTOCOL_VERSION,
+};
+
+/// Start a server which reports every event
+fn start_server(addr: &str) -> (Handle, Receiver<Event>) {
+    let (sender, events): (Sender<Event>, Receiver<Event>) = mpsc::channel();
+    let handle: Handle = Server::new()
+        .bind(addr)
+        .on_event(move |event: &Event| {
+            let _ = sender.send(event.clone());
+        })
+        .spawn()
+        .unwrap();
+    (handle, events)
+}
+
+/// Wait for the server to report that `nick` joined `room`
+fn wait_for_join(events: &Receiver<Event>, nick: &str, room: &str) {
+    let joined: Event = Event::Joined {
+        nick: nick.to_string(),
+        room: room.to_string(),
+    };
+    while events.recv_timeout(Duration::from_secs(10)).unwrap() != joined {}
+}
+
+/// The text of the next room message the client receives
+fn next_room_message(client: &Client) -> (String, String, String) {
+    for message in client.incoming() {
+        if let Message::MessageRoom { room, sender, text } = message.unwrap() {
+            return (room, sender, text);
+        }
+    }
+    panic!("the connection closed");
+}
+
+/// A bot with a few commands, answering in `ops`
+fn ops_bot(addr: SocketAddr) -> Bot {
+    Bot::new(&addr.to_string(), "opsbot")
+        .join("ops")
+        .reconnect_delay(Duration::from_millis(50))
+        .command("ping", |ctx: &Context| ctx.reply("pong"))
+        .command("echo", |ctx: &Context| ctx.reply_to_sender(&ctx.args))
+        .command("quit", |ctx: &Context| ctx.stop())
+        .pattern(
+            Regex::new(r"deployed (\S+) to (\S+)").unwrap(),
+            |ctx: &Context| {
+                let text: String = format!(
+                    "{} is live in {}",
+                    ctx.group(1).unwrap(),
+                    ctx.group(2).unwrap()
+                );
+                ctx.reply(&text)
+            },
+        )
+}
+
+fn room_message(room: &str, sender: &str, text: &str) -> (String, String, String) {
+    (room.to_string(), sender.to_string(), text.to_string())
+}
+
+#[test]
+fn test_bot_dispatches_commands_and_patterns() {
+    let (handle, events) = start_server("127.0.0.1:0");
+    let addr: SocketAddr = handle.local_addrs()[0];
+    let bot: thread::JoinHandle<rust_irc::Result<()>> = thread::spawn(move || ops_bot(addr).run());
+    wait_for_join(&events, "opsbot", "ops");
+
+    let westy: Client = Client::connect(&addr.to_string(), "westy", &Options::default()).unwrap();
+    westy.join("ops").unwrap();
+    wait_for_join(&events, "westy", "ops");
+    westy.message_room("ops", "!ping").unwrap();
+    assert_eq!(
+        next_room_message(&westy),
+        room_message("ops", "opsbot", "pong")
+    );
+    // Neither a command the bot does not have, nor the prefix inside a word, gets an answer
+    westy.message_room("ops", "!pingpong").unwrap();
+    westy.message_room("ops", "!echo  hello there ").unwrap();
+    assert_eq!(
+        next_room_message(&westy),
+        room_message("ops", "opsbot", "westy: hello there")
+    );
+    westy
+        .message_room("ops", "I deployed web to staging")
+        .unwrap();
+    assert_eq!(
+        next_room_message(&westy),
+        room_message("ops", "opsbot", "web is live in staging")
+    );
+
+    westy.message_room("ops", "!quit").unwrap();
+    bot.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_bot_reconnects_and_rejoins() {
+    let (handle, events) = start_server("127.0.0.1:0");
+    let addr: SocketAddr = handle.local_addrs()[0];
+    let bot: thread::JoinHandle<rust_irc::Result<()>> = thread::spawn(move || ops_bot(addr).run());
+    wait_for_join(&events, "opsbot", "ops");
+
+    // A new server on the same address, as after a restart
+    drop(handle);
+    let (handle, events) = start_server(&addr.to_string());
+    wait_for_join(&events, "opsbot", "ops");
+    assert_eq!(handle.members("ops").unwrap(), vec!["opsbot"]);
+
+    let westy: Client = Client::connect(&addr.to_string(), "westy", &Options::default()).unwrap();
+    westy.join("ops").unwrap();
+    westy.message_room("ops", "!ping").unwrap();
+    assert_eq!(
+        next_room_message(&westy),
+        room_message("ops", "opsbot", "pong")
+    );
+    westy.message_room("ops", "!quit").unwrap();
+    bot.join().unwrap().unwrap();
+}
+
+/// Accept the bot's connection and register it, playing the server's side.
+/// Returns the connection once the bot has joined `ops`
+fn accept_bot(listener: &TcpListener) -> (TcpStream, FrameReader<TcpStream>) {
+    let (mut writer, _) = listener.accept().unwrap();
+    let mut reader: FrameReader<TcpStream> = FrameReader::new(writer.try_clone().unwrap());
+    assert!(matches!(
+        read_message(&mut reader).unwrap(),
+        Message::JoinServer { .. }
+    ));
+    assert!(matches!(
+        read_message(&mut reader).unwrap(),
+        Message::RegisterNick { .. }
+    ));
+    let info: Message = Message::ServerInfo {
+        version: PROTOCOL_VERSION,
+        name: "silent".to_string(),
+        capabilities: vec![],
+    };
+    send(&mut writer, &info).unwrap();
+    send(&mut writer, &Message::ResponseOk).unwrap();
+    assert_eq!(
+        read_message(&mut reader).unwrap(),
+        Message::JoinRoom {
+            room: "ops".to_string()
+        }
+    );
+    (writer, reader)
+}
+
+#[test]
+fn test_bot_reconnects_to_a_silent_server() {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let bot: thread::JoinHandle<rust_irc::Result<()>> = thread::spawn(move || {
+        ops_bot(addr)
+            .keepalive(Duration::from_millis(50), Duration::from_millis(200))
+            .run()
+    });
+
+    // Accepted, then never answered, like a server which has hung or a connection which is half open
+    let (_writer, mut reader) = accept_bot(&listener);
+    assert_eq!(read_message(&mut reader).unwrap(), Message::KeepAlive);
+    loop {
+        match read_message(&mut reader) {
+            Ok(Message::KeepAlive) => {}
+            Ok(Message::Quit) => break,
+            other => panic!("expected keep alives and then a quit, got {:?}", other),
+        }
+    }
+
+    let (mut writer, mut reader) = accept_bot(&listener);
+    let quit: Message = Message::MessageRoom {
+        room: "ops".to_string(),
+        sender: "westy".to_string(),
+        text: "!quit".to_string(),
+    };
+    send(&mut writer, &quit).unwrap();
+    assert_eq!(read_message(&mut reader).unwrap(), Message::Quit);
+    bot.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_bot_reconnects_to_a_server_which_never_registers_it() {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let bot: thread::JoinHandle<rust_irc::Result<()>> = thread::spawn(move || {
+        ops_bot(addr)
+            .keepalive(Duration::from_millis(50), Duration::from_millis(200))
+            .run()
+    });
+
+    // Accepted, then never answered, like a server which has hung before the handshake
+    let (silent, _) = listener.accept().unwrap();
+    let (mut writer, mut reader) = accept_bot(&listener);
+    drop(silent);
+    let quit: Message = Message::MessageRoom {
+        room: "ops".to_string(),
+        sender: "westy".to_string(),
+        text: "!quit".to_string(),
+    };
+    send(&mut writer, &quit).unwrap();
+    assert_eq!(read_message(&mut reader).unwrap(), Message::Quit);
+    bot.join().unwrap().unwrap();
+}
@@ -0,0 +1,210 @@
+use std::thread;
+
+use rust_irc::{
+    client::Client,
+    framing::{FrameReader, MAX_FRAME_LEN},
+    protocol::{read_message, send, ErrorCode, Message},
+    transport::{duplex, Stream},
+    Error, PROTOCOL_VERSION,
+};
+
+/// The server's end of an in-memory connection, which has read the client's handshake and registration
+fn accept(server: Stream, info: bool, registered: Message) -> (Stream, FrameReader<Stream>) {
+    let mut writer: Stream = server.try_clone().unwrap();
+    let mut reader: FrameReader<Stream> = FrameReader::new(server);
+    assert!(matches!(
+        read_message(&mut reader).unwrap(),
+        Message::JoinServer {
+            version: PROTOCOL_VERSION,
+            ..
+        }
+    ));
+    let nick: String = match read_message(&mut reader).unwrap() {
+        Message::RegisterNick { nick, .. } => nick,
+        other => panic!("expected a registration, got {:?}", other),
+    };
+    assert_eq!(nick, "westy");
+    if info {
+        let info: Message = Message::ServerInfo {
+            version: PROTOCOL_VERSION,
+            name: "test".to_string(),
+            capabilities: vec!["history".to_string()],
+        };
+        send(&mut writer, &info).unwrap();
+    }
+    send(&mut writer, &registered).unwrap();
+    (writer, reader)
+}
+
+#[test]
+fn test_client_registers_and_exchanges_messages() {
+    let (client_end, server_end) = duplex();
+    let server: thread::JoinHandle<(Stream, FrameReader<Stream>)> =
+        thread::spawn(move || accept(Box::new(server_end), true, Message::ResponseOk));
+    let client: Client = Client::over(Box::new(client_end), "westy", None).unwrap();
+    let (mut writer, mut reader) = server.join().unwrap();
+    assert_eq!(client.server().name, "test");
+    assert_eq!(client.server().capabilities, vec!["history".to_string()]);
+
+    client.join("cat").unwrap();
+    client.message_room("cat", "hello").unwrap();
+    assert_eq!(
+        read_message(&mut reader).unwrap(),
+        Message::JoinRoom {
+            room: "cat".to_string()
+        }
+    );
+    assert_eq!(
+        read_message(&mut reader).unwrap(),
+        Message::MessageRoom {
+            room: "cat".to_string(),
+            sender: "westy".to_string(),
+            text: "hello".to_string(),
+        }
+    );
+
+    // Keep alive probes are answered without showing up as incoming messages
+    send(&mut writer, &Message::KeepAlive).unwrap();
+    assert_eq!(read_message(&mut reader).unwrap(), Message::ResponseOk);
+    let renamed: Message = Message::ChangeNick {
+        old: "westy".to_string(),
+        nick: "easty".to_string(),
+    };
+    send(&mut writer, &renamed).unwrap();
+    let mut incoming = client.incoming();
+    assert_eq!(incoming.next().unwrap().unwrap(), renamed);
+    assert_eq!(client.nick(), "easty");
+
+    let hello: Message = Message::PrivateMessage {
+        nick: "northy".to_string(),
+        text: "hi".to_string(),
+    };
+    send(&mut writer, &hello).unwrap();
+    drop(writer);
+    drop(reader);
+    assert_eq!(incoming.next().unwrap().unwrap(), hello);
+    assert!(matches!(incoming.next(), Some(Err(Error::Closed))));
+    assert!(incoming.next().is_none());
+}
+
+#[test]
+fn test_client_run_replies_until_closed() {
+    let (client_end, server_end) = duplex();
+    let server: thread::JoinHandle<Vec<Message>> = thread::spawn(move || {
+        let (mut writer, mut reader) = accept(Box::new(server_end), true, Message::ResponseOk);
+        let ping: Message = Message::PrivateMessage {
+            nick: "northy".to_string(),
+            text: "ping".to_string(),
+        };
+        send(&mut writer, &ping).unwrap();
+        send(&mut writer, &Message::Quit).unwrap();
+        let mut received: Vec<Message> = vec![];
+        while let Ok(message) = read_message(&mut reader) {
+            received.push(message);
+        }
+        received
+    });
+    let client: Client = Client::over(Box::new(client_end), "westy", None).unwrap();
+    client
+        .run(|client: &Client, message: Message| match message {
+            Message::PrivateMessage { nick, text } => client.private_message(&nick, &text),
+            Message::Quit => client.quit(),
+            _ => Ok(()),
+        })
+        .unwrap();
+    assert_eq!(
+        server.join().unwrap(),
+        vec![
+            Message::PrivateMessage {
+                nick: "northy".to_string(),
+                text: "ping".to_string(),
+            },
+            Message::Quit,
+        ]
+    );
+}
+
+#[test]
+fn test_client_registration_refused() {
+    let (client_end, server_end) = duplex();
+    let server: thread::JoinHandle<(Stream, FrameReader<Stream>)> = thread::spawn(move || {
+        accept(
+            Box::new(server_end),
+            true,
+            Message::Error(ErrorCode::NicknameCollision),
+        )
+    });
+    let refused: Error = Client::over(Box::new(client_end), "westy", None).unwrap_err();
+    assert!(matches!(
+        refused,
+        Error::Refused(ErrorCode::NicknameCollision)
+    ));
+    assert_eq!(
+        refused.to_string(),
+        "refused by the server: nickname already in use"
+    );
+    server.join().unwrap();
+
+    // A server over its limits refuses before the handshake
+    let (client_end, server_end) = duplex();
+    let server: thread::JoinHandle<(Stream, FrameReader<Stream>)> = thread::spawn(move || {
+        accept(
+            Box::new(server_end),
+            false,
+            Message::Error(ErrorCode::ServerFull),
+        )
+    });
+    assert!(matches!(
+        Client::over(Box::new(client_end), "westy", None),
+        Err(Error::Refused(ErrorCode::ServerFull))
+    ));
+    server.join().unwrap();
+}
+
+#[test]
+fn test_client_rejects_invalid_requests() {
+    let (client_end, server_end) = duplex();
+    let server: thread::JoinHandle<(Stream, FrameReader<Stream>)> =
+        thread::spawn(move || accept(Box::new(server_end), true, Message::ResponseOk));
+    let client: Client = Client::over(Box::new(client_end), "westy", None).unwrap();
+    let (writer, mut reader) = server.join().unwrap();
+
+    for room in ["", "black cat", "cat\n", "tab\tby"] {
+        assert!(matches!(client.join(room), Err(Error::Invalid(_))));
+        assert!(matches!(client.leave(room), Err(Error::Invalid(_))));
+        assert!(matches!(
+            client.message_room(room, "hello"),
+            Err(Error::Invalid(_))
+        ));
+    }
+    assert!(matches!(
+        client.private_message("north y", "hi"),
+        Err(Error::Invalid(_))
+    ));
+    assert!(matches!(client.change_nick(""), Err(Error::Invalid(_))));
+    assert_eq!(
+        client.change_nick("we sty").unwrap_err().to_string(),
+        "invalid request: the nickname \"we sty\" contains whitespace or control characters"
+    );
+    let long: String = "a".repeat(MAX_FRAME_LEN);
+    assert!(matches!(
+        client.message_room("cat", &long),
+        Err(Error::Invalid(_))
+    ));
+
+    // None of them were sent, so the next thing the server reads is a valid request
+    client.join("cat").unwrap();
+    assert_eq!(
+        read_message(&mut reader).unwrap(),
+        Message::JoinRoom {
+            room: "cat".to_string()
+        }
+    );
+    drop(writer);
+
+    let (client_end, _server_end) = duplex();
+    assert!(matches!(
+        Client::over(Box::new(client_end), "", None),
+        Err(Error::Invalid(_))
+    ));
+}
@@ -0,0 +1,118 @@
+use std::io::{Cursor, ErrorKind, Read};
+use std::time::Duration;
+
+use rust_irc::{
+    buf_helpers::{one_op_buf, three_param_buf},
+    codes,
+    framing::{
+        frame, write_frame, write_frame_async, AsyncFrameReader, FrameReader, HEADER_LEN,
+        MAX_FRAME_LEN,
+    },
+};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+
+/// A reader which hands out at most `step` bytes per read, to simulate TCP splitting messages
+struct Trickle {
+    data: Vec<u8>,
+    pos: usize,
+    step: usize,
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let end: usize = (self.pos + self.step)
+            .min(self.data.len())
+            .min(self.pos + buf.len());
+        let size: usize = end - self.pos;
+        buf[..size].copy_from_slice(&self.data[self.pos..end]);
+        self.pos = end;
+        Ok(size)
+    }
+}
+
+#[test]
+pub fn test_frame() {
+    let framed: Vec<u8> = frame(&one_op_buf(codes::RESPONSE_OK));
+    assert_eq!(framed, [0, 0, 0, 1, codes::RESPONSE_OK]);
+}
+
+#[test]
+pub fn test_write_frame_too_large() {
+    let mut out: Vec<u8> = vec![];
+    let payload: Vec<u8> = vec![0; MAX_FRAME_LEN + 1];
+    let result: std::io::Result<()> = write_frame(&mut out, &payload);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(out.is_empty());
+}
+
+#[test]
+pub fn test_read_coalesced_frames() {
+    let first: Vec<u8> = three_param_buf(codes::MESSAGE_ROOM, "cat", "westy", "hello");
+    let second: Vec<u8> = three_param_buf(codes::MESSAGE_ROOM, "cat", "westy", "world");
+    let mut wire: Vec<u8> = vec![];
+    write_frame(&mut wire, &first).unwrap();
+    write_frame(&mut wire, &second).unwrap();
+
+    let mut reader: FrameReader<Cursor<Vec<u8>>> = FrameReader::new(Cursor::new(wire));
+    assert_eq!(reader.read_frame().unwrap(), Some(first));
+    assert_eq!(reader.read_frame().unwrap(), Some(second));
+    assert_eq!(reader.read_frame().unwrap(), None);
+}
+
+#[test]
+pub fn test_read_split_frames() {
+    let message: String = "a".repeat(3000);
+    let payload: Vec<u8> = three_param_buf(codes::MESSAGE_ROOM, "cat", "westy", &message);
+    let mut reader: FrameReader<Trickle> = FrameReader::new(Trickle {
+        data: frame(&payload),
+        pos: 0,
+        step: 3,
+    });
+    assert_eq!(reader.read_frame().unwrap(), Some(payload));
+    assert_eq!(reader.read_frame().unwrap(), None);
+}
+
+#[test]
+pub fn test_read_truncated_frame() {
+    let mut wire: Vec<u8> = frame(&three_param_buf(codes::MESSAGE_ROOM, "cat", "dog", "frog"));
+    wire.truncate(HEADER_LEN + 2);
+    let mut reader: FrameReader<Cursor<Vec<u8>>> = FrameReader::new(Cursor::new(wire));
+    assert_eq!(
+        reader.read_frame().unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+pub fn test_read_oversized_frame() {
+    let wire: Vec<u8> = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+    let mut reader: FrameReader<Cursor<Vec<u8>>> = FrameReader::new(Cursor::new(wire));
+    assert_eq!(
+        reader.read_frame().unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+}
+
+#[tokio::test]
+pub async fn test_async_read_split_frames() {
+    let payload: Vec<u8> = three_param_buf(codes::MESSAGE_ROOM, "cat", "westy", "hello");
+    let (mut writer, reader) = tokio::io::duplex(1024);
+    let mut reader: AsyncFrameReader<DuplexStream> = AsyncFrameReader::new(reader);
+    let wire: Vec<u8> = frame(&payload);
+    writer.write_all(&wire[..HEADER_LEN + 2]).await.unwrap();
+
+    // Giving up on a read part way through a frame must not lose the bytes read so far
+    let waiting = tokio::time::timeout(Duration::from_millis(20), reader.read_frame());
+    assert!(waiting.await.is_err());
+    writer.write_all(&wire[HEADER_LEN + 2..]).await.unwrap();
+    write_frame_async(&mut writer, &payload).await.unwrap();
+    assert_eq!(reader.read_frame().await.unwrap(), Some(payload.clone()));
+    assert_eq!(reader.read_frame().await.unwrap(), Some(payload));
+
+    writer.write_all(&wire[..HEADER_LEN]).await.unwrap();
+    drop(writer);
+    assert_eq!(
+        reader.read_frame().await.unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
@@ -2,7 +2,7 @@ use rust_irc::{
     buf_helpers::{
         one_op_buf, one_param_buf, three_param_buf, two_op_buf, two_param_buf, SPACE_BYTES,
     },
-    codes,
+    codes, host_with_port,
 };
 
 #[test]
@@ -56,3 +56,13 @@ pub fn test_three_param_buf() {
     let result: Vec<u8> = three_param_buf(codes::MESSAGE, "cat", "dog", "frog");
     assert_eq!(result, checker_buf);
 }
+
+#[test]
+pub fn test_host_with_port() {
+    assert_eq!(host_with_port("0.0.0.0", 6667), "0.0.0.0:6667");
+    assert_eq!(host_with_port("127.0.0.1:7000", 6667), "127.0.0.1:7000");
+    assert_eq!(host_with_port("::1", 6667), "[::1]:6667");
+    assert_eq!(host_with_port("[::]:7000", 6667), "[::]:7000");
+    assert_eq!(host_with_port("localhost", 7000), "localhost:7000");
+    assert_eq!(host_with_port("chat.local:7000", 6667), "chat.local:7000");
+}
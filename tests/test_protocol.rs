@@ -0,0 +1,264 @@
+use rust_irc::{
+    buf_helpers::{one_op_buf, one_param_buf, two_op_buf},
+    codes,
+    framing::{write_frame, FrameReader},
+    protocol::{read_message, send, DecodeError, ErrorCode, MembershipChange, Message},
+    Error,
+};
+
+fn round_trip(message: Message) {
+    let encoded: Vec<u8> = message.encode();
+    assert_eq!(encoded[0], message.opcode());
+    assert_eq!(Message::decode(&encoded), Ok(message));
+}
+
+#[test]
+pub fn test_round_trip_all_messages() {
+    round_trip(Message::JoinRoom {
+        room: "cat".to_string(),
+    });
+    round_trip(Message::JoinServer {
+        version: 1,
+        capabilities: vec![],
+    });
+    round_trip(Message::JoinServer {
+        version: 2,
+        capabilities: vec!["topics".to_string(), "history".to_string()],
+    });
+    round_trip(Message::LeaveRoom {
+        room: "cat".to_string(),
+    });
+    round_trip(Message::ListRooms);
+    round_trip(Message::Message {
+        text: "hello world".to_string(),
+    });
+    round_trip(Message::RegisterNick {
+        nick: "westy".to_string(),
+        password: None,
+    });
+    round_trip(Message::RegisterNick {
+        nick: "westy".to_string(),
+        password: Some("correct horse battery".to_string()),
+    });
+    round_trip(Message::RegisterAccount {
+        password: "correct horse battery".to_string(),
+    });
+    round_trip(Message::ListUsers);
+    round_trip(Message::ListUsersInRoom {
+        room: "cat".to_string(),
+    });
+    round_trip(Message::MessageRoom {
+        room: "cat".to_string(),
+        sender: "westy".to_string(),
+        text: "hello there world".to_string(),
+    });
+    round_trip(Message::ServerInfo {
+        version: 1,
+        name: "rust-irc".to_string(),
+        capabilities: vec!["topics".to_string()],
+    });
+    round_trip(Message::Quit);
+    round_trip(Message::KeepAlive);
+    round_trip(Message::Response(
+        "Joined cat. Current rooms: cat".to_string(),
+    ));
+    round_trip(Message::ResponseOk);
+    round_trip(Message::Error(ErrorCode::NotInRoom));
+    round_trip(Message::Error(ErrorCode::BadCredentials));
+    round_trip(Message::Error(ErrorCode::Unknown(0x7F)));
+    round_trip(Message::PrivateMessage {
+        nick: "easty".to_string(),
+        text: "hello there".to_string(),
+    });
+    round_trip(Message::Kick {
+        room: "cat".to_string(),
+        nick: "easty".to_string(),
+    });
+    round_trip(Message::Ban {
+        room: "cat".to_string(),
+        nick: "easty".to_string(),
+    });
+    round_trip(Message::Unban {
+        room: "cat".to_string(),
+        nick: "easty".to_string(),
+    });
+    round_trip(Message::Op {
+        room: "cat".to_string(),
+        nick: "easty".to_string(),
+    });
+    round_trip(Message::Deop {
+        room: "cat".to_string(),
+        nick: "easty".to_string(),
+    });
+    round_trip(Message::SetTopic {
+        room: "cat".to_string(),
+        topic: "all about cats".to_string(),
+    });
+    round_trip(Message::SetTopic {
+        room: "cat".to_string(),
+        topic: "".to_string(),
+    });
+    round_trip(Message::GetTopic {
+        room: "cat".to_string(),
+    });
+    round_trip(Message::ChangeNick {
+        old: "westy".to_string(),
+        nick: "easty".to_string(),
+    });
+    for change in [
+        MembershipChange::Joined,
+        MembershipChange::Left,
+        MembershipChange::Quit,
+        MembershipChange::TimedOut,
+    ] {
+        round_trip(Message::Membership {
+            room: "cat".to_string(),
+            nick: "easty".to_string(),
+            change,
+        });
+    }
+    round_trip(Message::GetHistory {
+        room: "cat".to_string(),
+        count: 20,
+    });
+    round_trip(Message::History {
+        room: "cat".to_string(),
+        time: 1_700_000_000,
+        sender: "westy".to_string(),
+        text: "hello there world".to_string(),
+    });
+    round_trip(Message::History {
+        room: "cat".to_string(),
+        time: 0,
+        sender: "westy".to_string(),
+        text: "".to_string(),
+    });
+    for persistent in [true, false] {
+        round_trip(Message::Persist {
+            room: "cat".to_string(),
+            persistent,
+        });
+    }
+}
+
+#[test]
+pub fn test_encode_matches_buf_helpers() {
+    let message: Message = Message::JoinRoom {
+        room: "cat".to_string(),
+    };
+    assert_eq!(message.encode(), one_param_buf(codes::JOIN_ROOM, "cat"));
+    let error: Message = Message::Error(ErrorCode::AlreadyInRoom);
+    assert_eq!(
+        error.encode(),
+        two_op_buf(codes::ERROR, codes::error::ALREADY_IN_ROOM)
+    );
+}
+
+#[test]
+pub fn test_decode_errors() {
+    assert_eq!(Message::decode(&[]), Err(DecodeError::Empty));
+    assert_eq!(
+        Message::decode(&[0x7F]),
+        Err(DecodeError::UnknownOpcode(0x7F))
+    );
+    assert_eq!(
+        Message::decode(&[codes::ERROR]),
+        Err(DecodeError::Malformed(codes::ERROR))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::JOIN_ROOM, "")),
+        Err(DecodeError::Malformed(codes::JOIN_ROOM))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::JOIN_ROOM, "cat dog")),
+        Err(DecodeError::Malformed(codes::JOIN_ROOM))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::MESSAGE_ROOM, "cat")),
+        Err(DecodeError::Malformed(codes::MESSAGE_ROOM))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::JOIN_SERVER, "one")),
+        Err(DecodeError::Malformed(codes::JOIN_SERVER))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::SERVER_INFO, "1")),
+        Err(DecodeError::Malformed(codes::SERVER_INFO))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::KICK, "cat easty westy")),
+        Err(DecodeError::Malformed(codes::KICK))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::CHANGE_NICK, "westy")),
+        Err(DecodeError::Malformed(codes::CHANGE_NICK))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::MEMBERSHIP, "cat easty napped")),
+        Err(DecodeError::Malformed(codes::MEMBERSHIP))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::GET_HISTORY, "cat many")),
+        Err(DecodeError::Malformed(codes::GET_HISTORY))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::HISTORY, "cat yesterday westy hi")),
+        Err(DecodeError::Malformed(codes::HISTORY))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::PERSIST, "cat yes")),
+        Err(DecodeError::Malformed(codes::PERSIST))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::REGISTER_NICK, "westy ")),
+        Err(DecodeError::Malformed(codes::REGISTER_NICK))
+    );
+    assert_eq!(
+        Message::decode(&one_op_buf(codes::REGISTER_ACCOUNT)),
+        Err(DecodeError::Malformed(codes::REGISTER_ACCOUNT))
+    );
+    assert_eq!(
+        Message::decode(&one_param_buf(codes::SET_TOPIC, "cat")),
+        Ok(Message::SetTopic {
+            room: "cat".to_string(),
+            topic: "".to_string(),
+        })
+    );
+    assert_eq!(
+        Message::decode(&[codes::RESPONSE, 0xFF, 0xFE]),
+        Err(DecodeError::InvalidUtf8(codes::RESPONSE))
+    );
+}
+
+#[test]
+pub fn test_send_is_framed() {
+    let mut wire: Vec<u8> = vec![];
+    send(&mut wire, &Message::ListRooms).unwrap();
+    send(&mut wire, &Message::Error(ErrorCode::EmptyRoom)).unwrap();
+    let mut reader: FrameReader<&[u8]> = FrameReader::new(&wire[..]);
+    let first: Vec<u8> = reader.read_frame().unwrap().unwrap();
+    let second: Vec<u8> = reader.read_frame().unwrap().unwrap();
+    assert_eq!(Message::decode(&first), Ok(Message::ListRooms));
+    assert_eq!(
+        Message::decode(&second),
+        Ok(Message::Error(ErrorCode::EmptyRoom))
+    );
+}
+
+#[test]
+pub fn test_read_message_errors() {
+    let mut wire: Vec<u8> = vec![];
+    write_frame(&mut wire, &[0x7F]).unwrap();
+    send(&mut wire, &Message::KeepAlive).unwrap();
+    wire.extend_from_slice(&[0, 0]);
+    let mut reader: FrameReader<&[u8]> = FrameReader::new(&wire[..]);
+    assert!(matches!(
+        read_message(&mut reader),
+        Err(Error::Decode(DecodeError::UnknownOpcode(0x7F)))
+    ));
+    assert_eq!(read_message(&mut reader).unwrap(), Message::KeepAlive);
+    assert!(matches!(read_message(&mut reader), Err(Error::Io(_))));
+
+    let mut closed: FrameReader<&[u8]> = FrameReader::new(&[][..]);
+    assert!(matches!(read_message(&mut closed), Err(Error::Closed)));
+}
@@ -0,0 +1,174 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_irc::{
+    client::{Client, Options},
+    protocol::Message,
+    server::{Event, Handle, Server},
+    Error,
+};
+
+/// The next event the server reports, failing the test if none arrives in time
+fn next_event(events: &Receiver<Event>) -> Event {
+    events
+        .recv_timeout(Duration::from_secs(5))
+        .expect("expected an event")
+}
+
+/// The next message for the client which is not an answer to one of its own requests
+fn next_message(client: &Client) -> Message {
+    client
+        .incoming()
+        .map(|message: rust_irc::Result<Message>| message.unwrap())
+        .find(|message: &Message| {
+            !matches!(
+                message,
+                Message::ResponseOk | Message::Response(_) | Message::Membership { .. }
+            )
+        })
+        .unwrap()
+}
+
+#[test]
+fn test_embedded_server() {
+    let (sender, events): (Sender<Event>, Receiver<Event>) = mpsc::channel();
+    let handle: Handle = Server::new()
+        .bind("127.0.0.1:0")
+        .on_event(move |event: &Event| {
+            let _ = sender.send(event.clone());
+        })
+        .spawn()
+        .unwrap();
+    let addr: SocketAddr = handle.local_addrs()[0];
+    assert_ne!(addr.port(), 0);
+
+    let westy: Client = Client::connect(&addr.to_string(), "westy", &Options::default()).unwrap();
+    assert!(matches!(next_event(&events), Event::Registered { nick, .. } if nick == "westy"));
+    let easty: Client = Client::connect(&addr.to_string(), "easty", &Options::default()).unwrap();
+    assert!(matches!(next_event(&events), Event::Registered { nick, .. } if nick == "easty"));
+    westy.join("cat").unwrap();
+    assert_eq!(
+        next_event(&events),
+        Event::Joined {
+            nick: "westy".to_string(),
+            room: "cat".to_string()
+        }
+    );
+    easty.join("cat").unwrap();
+    assert_eq!(
+        next_event(&events),
+        Event::Joined {
+            nick: "easty".to_string(),
+            room: "cat".to_string()
+        }
+    );
+    assert_eq!(handle.users(), vec!["easty", "westy"]);
+    assert_eq!(handle.rooms(), vec!["cat"]);
+    assert_eq!(handle.members("cat").unwrap(), vec!["westy", "easty"]);
+    assert!(handle.members("dog").is_none());
+
+    westy.message_room("cat", "hello").unwrap();
+    assert_eq!(
+        next_event(&events),
+        Event::RoomMessage {
+            room: "cat".to_string(),
+            sender: "westy".to_string(),
+            text: "hello".to_string(),
+        }
+    );
+    assert_eq!(
+        next_message(&easty),
+        Message::MessageRoom {
+            room: "cat".to_string(),
+            sender: "westy".to_string(),
+            text: "hello".to_string(),
+        }
+    );
+    handle.broadcast("maintenance soon");
+    assert_eq!(
+        next_message(&easty),
+        Message::Message {
+            text: "maintenance soon".to_string()
+        }
+    );
+
+    westy.quit().unwrap();
+    assert_eq!(
+        next_event(&events),
+        Event::Disconnected {
+            nick: "westy".to_string()
+        }
+    );
+    assert_eq!(handle.members("cat").unwrap(), vec!["easty"]);
+
+    // Shutting down tells the remaining clients, then closes their connections
+    handle.shutdown();
+    assert_eq!(next_message(&easty), Message::Quit);
+    assert!(matches!(
+        easty.incoming().next(),
+        Some(Err(Error::Closed)) | Some(Err(Error::Io(_)))
+    ));
+    assert!(Client::connect(&addr.to_string(), "northy", &Options::default()).is_err());
+}
+
+#[test]
+fn test_embedded_server_fails_to_start() {
+    let taken: Handle = Server::new().bind("127.0.0.1:0").spawn().unwrap();
+    let addr: String = taken.local_addrs()[0].to_string();
+    let error: io::Error = Server::new().bind(&addr).spawn().err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    assert!(error.to_string().contains(&addr));
+
+    let error: io::Error = Server::new()
+        .bind("127.0.0.1:0")
+        .tls("/nonexistent/cert.pem", "/nonexistent/key.pem")
+        .spawn()
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("TLS certificate"));
+}
+
+#[test]
+fn test_embedded_server_saves_persistent_rooms() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("rust-irc-rooms-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let handle: Handle = Server::new()
+        .bind("127.0.0.1:0")
+        .data_dir(&dir)
+        .spawn()
+        .unwrap();
+    let addr: String = handle.local_addrs()[0].to_string();
+    let westy: Client = Client::connect(&addr, "westy", &Options::default()).unwrap();
+    westy.join("cat").unwrap();
+    let persist: Message = Message::Persist {
+        room: "cat".to_string(),
+        persistent: true,
+    };
+    westy.send(&persist).unwrap();
+
+    // Saved in the background while the server keeps running
+    let saved: Instant = Instant::now() + Duration::from_secs(5);
+    while !fs::read_to_string(dir.join("rooms.txt"))
+        .is_ok_and(|text: String| text.contains("room cat"))
+    {
+        assert!(Instant::now() < saved, "the room was not saved");
+        thread::sleep(Duration::from_millis(10));
+    }
+    westy.quit().unwrap();
+    drop(westy);
+    handle.shutdown();
+
+    let handle: Handle = Server::new()
+        .bind("127.0.0.1:0")
+        .data_dir(&dir)
+        .spawn()
+        .unwrap();
+    assert_eq!(handle.rooms(), vec!["cat"]);
+    handle.shutdown();
+    fs::remove_dir_all(&dir).unwrap();
+}
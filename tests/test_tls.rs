@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+use rust_irc::{
+    client::{Client, Options},
+    protocol::Message,
+    server::{Handle, Server},
+    tls::{cert_fingerprint, format_fingerprint, host_name, parse_fingerprint, Verify},
+};
+
+/// Write a certificate and key as PEM files, returning their paths
+fn write_server_files(name: &str, cert_pem: &str, key_pem: &str) -> (PathBuf, PathBuf) {
+    let dir: PathBuf = std::env::temp_dir().join(format!("rust-irc-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert: PathBuf = dir.join(format!("{}.crt", name));
+    let key: PathBuf = dir.join(format!("{}.key", name));
+    fs::write(&cert, cert_pem).unwrap();
+    fs::write(&key, key_pem).unwrap();
+    (cert, key)
+}
+
+/// Start an embedded server which serves TLS with the certificate and key, returning its port
+fn tls_server(cert: &PathBuf, key: &PathBuf) -> (Handle, u16) {
+    let handle: Handle = Server::new()
+        .bind("127.0.0.1:0")
+        .tls(cert, key)
+        .spawn()
+        .unwrap();
+    let port: u16 = handle.local_addrs()[0].port();
+    (handle, port)
+}
+
+fn connect(host: &str, nick: &str, verify: Verify) -> rust_irc::Result<Client> {
+    let options: Options = Options {
+        tls: Some(verify),
+        ..Options::default()
+    };
+    Client::connect(host, nick, &options)
+}
+
+/// Have two clients talk in a room over TLS, with messages larger than one TLS record
+fn exchange_messages(host: &str, verify: Verify) {
+    let westy: Client = connect(host, "westy", verify.clone()).unwrap();
+    let easty: Client = connect(host, "easty", verify).unwrap();
+    westy.join("cat").unwrap();
+    easty.join("cat").unwrap();
+    // Once easty's join is answered, westy's message is sure to reach it
+    for message in easty.incoming() {
+        if let Message::Response(_) = message.unwrap() {
+            break;
+        }
+    }
+    let sent: Vec<String> = (0..20)
+        .map(|i: i32| format!("message {} {}", i, "x".repeat(10000)))
+        .collect();
+    for text in &sent {
+        westy.message_room("cat", text).unwrap();
+    }
+    let received: Vec<String> = easty
+        .incoming()
+        .filter_map(
+            |message: rust_irc::Result<Message>| match message.unwrap() {
+                Message::MessageRoom { text, .. } => Some(text),
+                _ => None,
+            },
+        )
+        .take(sent.len())
+        .collect();
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn test_fingerprints_and_host_names() {
+    let fingerprint: [u8; 32] = core::array::from_fn(|i: usize| i as u8 * 7);
+    let text: String = format_fingerprint(&fingerprint);
+    assert!(text.starts_with("00:07:0E:15"));
+    assert_eq!(parse_fingerprint(&text), Some(fingerprint));
+    assert_eq!(
+        parse_fingerprint(&text.replace(':', "").to_lowercase()),
+        Some(fingerprint)
+    );
+    assert_eq!(parse_fingerprint("00:07"), None);
+    assert_eq!(parse_fingerprint(&text.replace("0E", "0G")), None);
+
+    assert_eq!(host_name("chat.local:6667"), "chat.local");
+    assert_eq!(host_name("[::1]:6667"), "::1");
+    assert_eq!(host_name("127.0.0.1:6697"), "127.0.0.1");
+    assert_eq!(host_name("chat.local"), "chat.local");
+}
+
+#[test]
+fn test_tls_with_pinned_self_signed_certificate() {
+    let CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, key_path) =
+        write_server_files("pinned", &cert.pem(), &key_pair.serialize_pem());
+    let fingerprint: [u8; 32] = cert_fingerprint(&cert_path).unwrap();
+
+    let (handle, port) = tls_server(&cert_path, &key_path);
+    // Pinning ignores the name, so the address is enough
+    exchange_messages(
+        &format!("127.0.0.1:{}", port),
+        Verify::Fingerprint(fingerprint),
+    );
+    handle.shutdown();
+}
+
+#[test]
+fn test_tls_rejects_other_certificates() {
+    let CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, key_path) =
+        write_server_files("rejected", &cert.pem(), &key_pair.serialize_pem());
+
+    let (handle, port) = tls_server(&cert_path, &key_path);
+    let host: String = format!("localhost:{}", port);
+    assert!(connect(&host, "westy", Verify::Fingerprint([0; 32])).is_err());
+    // Not issued by a public certificate authority
+    assert!(connect(&host, "westy", Verify::PublicRoots).is_err());
+    // The server carries on serving those who do trust it
+    let fingerprint: [u8; 32] = cert_fingerprint(&cert_path).unwrap();
+    assert!(connect(&host, "westy", Verify::Fingerprint(fingerprint)).is_ok());
+    handle.shutdown();
+}
+
+#[test]
+fn test_tls_with_certificate_authority_file() {
+    let ca_key: KeyPair = KeyPair::generate().unwrap();
+    let mut ca_params: CertificateParams = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_cert: rcgen::Certificate = ca_params.self_signed(&ca_key).unwrap();
+    let key: KeyPair = KeyPair::generate().unwrap();
+    let cert: rcgen::Certificate = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&key, &ca_cert, &ca_key)
+        .unwrap();
+    let (ca_path, _) = write_server_files("ca", &ca_cert.pem(), &ca_key.serialize_pem());
+    let (cert_path, key_path) = write_server_files("issued", &cert.pem(), &key.serialize_pem());
+
+    let (handle, port) = tls_server(&cert_path, &key_path);
+    exchange_messages(
+        &format!("localhost:{}", port),
+        Verify::CaFile(ca_path.clone()),
+    );
+
+    // The certificate does not name the address
+    assert!(connect(
+        &format!("127.0.0.1:{}", port),
+        "westy",
+        Verify::CaFile(ca_path)
+    )
+    .is_err());
+    handle.shutdown();
+}
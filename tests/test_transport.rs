@@ -0,0 +1,98 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::thread;
+use std::time::Duration;
+
+use rust_irc::{
+    framing::FrameReader,
+    protocol::{read_message, send, Message},
+    transport::{duplex, MemoryStream, Stream, Transport},
+    Error,
+};
+
+#[test]
+fn test_duplex_carries_messages_both_ways() {
+    let (client, server) = duplex();
+    let mut client: Stream = Box::new(client);
+    let mut server: Stream = Box::new(server);
+    let mut client_reader: FrameReader<Stream> = FrameReader::new(client.try_clone().unwrap());
+    let mut server_reader: FrameReader<Stream> = FrameReader::new(server.try_clone().unwrap());
+
+    let ping: Message = Message::Message {
+        text: "ping".to_string(),
+    };
+    send(&mut client, &ping).unwrap();
+    assert_eq!(read_message(&mut server_reader).unwrap(), ping);
+    send(&mut server, &Message::ResponseOk).unwrap();
+    assert_eq!(
+        read_message(&mut client_reader).unwrap(),
+        Message::ResponseOk
+    );
+    assert_eq!(client.peer_addr().unwrap().ip().to_string(), "127.0.0.1");
+
+    drop(client);
+    drop(client_reader);
+    assert!(matches!(
+        read_message(&mut server_reader),
+        Err(Error::Closed)
+    ));
+}
+
+#[test]
+fn test_duplex_reads_wait_for_writes() {
+    let (mut client, server) = duplex();
+    let reader: thread::JoinHandle<Vec<u8>> = thread::spawn(move || {
+        let mut received: Vec<u8> = vec![];
+        let mut reader: MemoryStream = server;
+        reader.read_to_end(&mut received).unwrap();
+        received
+    });
+    for chunk in [&b"hello "[..], b"there"] {
+        client.write_all(chunk).unwrap();
+    }
+    drop(client);
+    assert_eq!(reader.join().unwrap(), b"hello there");
+}
+
+#[test]
+fn test_duplex_shutdown() {
+    let (mut client, server) = duplex();
+    let mut server: Stream = Box::new(server);
+    client.write_all(b"queued").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut received: Vec<u8> = vec![];
+    server.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"queued");
+    assert_eq!(
+        client.write(b"late").unwrap_err().kind(),
+        ErrorKind::BrokenPipe
+    );
+
+    // Dropping every handle to one end closes the connection in both directions
+    let clone: Stream = client.try_clone().unwrap();
+    drop(client);
+    assert!(server.write(b"still open").is_ok());
+    drop(clone);
+    assert!(server.write(b"gone").is_err());
+}
+
+#[test]
+fn test_duplex_read_timeout() {
+    let (mut client, server) = duplex();
+    let mut server: Stream = Box::new(server);
+    server
+        .set_read_timeout(Some(Duration::from_millis(20)))
+        .unwrap();
+    let mut buf: [u8; 8] = [0; 8];
+    assert_eq!(
+        server.read(&mut buf).unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+    client.write_all(b"late").unwrap();
+    assert_eq!(server.read(&mut buf).unwrap(), 4);
+
+    // Cleared, reads wait for the other end again
+    server.set_read_timeout(None).unwrap();
+    drop(client);
+    assert_eq!(server.read(&mut buf).unwrap(), 0);
+}